@@ -0,0 +1,90 @@
+use crate::{
+    durability::WriteOptions, unix_now, BatchMarker, FileIO, KeyDirEntry, Record, Result,
+    SStStorage,
+};
+
+/// Puts and deletes that are written together: after a crash, either all of them are
+/// there or none are.
+///
+/// In the log the batch sits between a begin and a commit marker, and
+/// `load_db_from_disk` ignores any batch whose commit marker never made it to disk.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    records: Vec<Record>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.records.push(Record::new(key, value, false));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.records.push(Record::new(key, &[], true));
+        self
+    }
+
+    /// Number of puts and deletes in the batch.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Applies every put and delete in `batch`, in order, as one write. Later entries
+    /// for a key win over earlier ones.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        self.write_batch_with_options(batch, WriteOptions::default())
+    }
+
+    pub fn write_batch_with_options(
+        &mut self,
+        batch: &WriteBatch,
+        write_options: WriteOptions,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let write_time = Some(unix_now());
+        let mut records = Vec::with_capacity(batch.len() + 2);
+        records.push(Record::batch_marker(BatchMarker::Begin));
+        records.extend(batch.records.iter().map(|record| Record {
+            write_time,
+            ..record.clone()
+        }));
+        records.push(Record::batch_marker(BatchMarker::Commit));
+
+        let placed = self.append_all(&records, write_options)?;
+        // The keydir only changes once the whole batch, commit marker and all, is written.
+        for (record, (offset, length)) in records.into_iter().zip(placed) {
+            if record.batch_marker.is_some() {
+                continue;
+            }
+            if record.tombstone {
+                self.index.remove(&record.key);
+            } else {
+                let entry = KeyDirEntry {
+                    file_id: self.active_id,
+                    offset,
+                    length,
+                    write_time,
+                    expires_at: None,
+                };
+                self.insert_key(record.key, entry);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use std::{io, str::FromStr};
+
+use crate::{FileIO, SStStorage};
+
+/// Level zstd compresses at, its own default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Values smaller than this are not worth compressing, unless the options say otherwise.
+const DEFAULT_MIN_SIZE: usize = 64;
+
+/// The algorithm a compressed value was stored with. The record's flags say which.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Fast, with a modest ratio.
+    Lz4,
+    /// Slower, with a better ratio.
+    Zstd,
+}
+
+/// How values are compressed as they are written. Reads decompress whatever they find,
+/// whatever the storage was opened with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Values shorter than this many bytes are stored as they are.
+    pub min_size: usize,
+}
+
+/// How well the values written since the storage was opened compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    /// Records appended.
+    pub records: u64,
+    /// How many of them were stored with their value compressed.
+    pub records_compressed: u64,
+    /// What the records would have taken up on disk uncompressed.
+    pub raw_bytes: u64,
+    /// What they took up on disk.
+    pub stored_bytes: u64,
+}
+
+impl Compression {
+    /// Compresses values of at least `DEFAULT_MIN_SIZE` bytes with `codec`.
+    pub fn new(codec: Codec) -> Self {
+        Compression {
+            codec,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    /// The value compressed, or `None` if it is too short or does not get any shorter.
+    pub(crate) fn compress(&self, value: &[u8]) -> Option<Vec<u8>> {
+        if value.len() < self.min_size {
+            return None;
+        }
+        let compressed = match self.codec {
+            Codec::Lz4 => lz4_flex::compress_prepend_size(value),
+            Codec::Zstd => zstd::bulk::compress(value, ZSTD_LEVEL).ok()?,
+        };
+        (compressed.len() < value.len()).then_some(compressed)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    /// Parses `lz4` or `zstd`, optionally followed by `:<min size>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codec, min_size) = match s.split_once(':') {
+            Some((codec, min_size)) => (codec, Some(min_size)),
+            None => (s, None),
+        };
+        let codec = match codec {
+            "lz4" => Codec::Lz4,
+            "zstd" => Codec::Zstd,
+            _ => return Err(format!("Unknown compression {:?}", s)),
+        };
+        let mut compression = Compression::new(codec);
+        if let Some(min_size) = min_size {
+            compression.min_size = min_size
+                .parse()
+                .map_err(|e| format!("Invalid compression {:?}: {}", s, e))?;
+        }
+        Ok(compression)
+    }
+}
+
+impl Codec {
+    /// Restores a value `Compression::compress` compressed with this codec.
+    pub(crate) fn decompress(&self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(stored)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Codec::Zstd => zstd::stream::decode_all(stored),
+        }
+    }
+}
+
+impl CompressionStats {
+    /// Bytes the records would have taken up uncompressed for every byte they took up,
+    /// 1.0 before anything is written.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// How well the records appended since the storage was opened compressed.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression_stats
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{FileIO, KeyDirEntry, Result, SStStorage};
+
+/// Identifies the value a key held when it was read, for `compare_and_set_version`.
+///
+/// It is where that value sits on disk, so every write, delete or new expiry of the
+/// key gives it a new version, even one that writes the same value again. A merge
+/// that copies the value does too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Version(KeyDirEntry);
+
+impl<T: FileIO> SStStorage<T> {
+    /// Reads a key together with its version.
+    pub fn get_with_version(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Version)>> {
+        match self.live_entry(key) {
+            Some(entry) => Ok(Some((self.read_value(entry)?, Version(*entry)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes `value` only if `key` does not exist or has expired. Returns whether it
+    /// was written.
+    pub fn put_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        if self.live_entry(key).is_some() {
+            return Ok(false);
+        }
+        self.write(key, value, false, None)?;
+        Ok(true)
+    }
+
+    /// Writes `value` only if `key` exists. Returns whether it was written. The key
+    /// keeps its expiry.
+    pub fn update_if_present(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let expires_at = match self.live_entry(key) {
+            Some(entry) => entry.expires_at,
+            None => return Ok(false),
+        };
+        self.write(key, value, false, expires_at)?;
+        Ok(true)
+    }
+
+    /// Writes `value` only if the current value of `key` is `expected`, where `None`
+    /// means the key must not exist. Returns whether it was written. A key that exists
+    /// keeps its expiry.
+    pub fn compare_and_set(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<bool> {
+        if self.read(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let expires_at = self.live_entry(key).and_then(|entry| entry.expires_at);
+        self.write(key, value, false, expires_at)?;
+        Ok(true)
+    }
+
+    /// Like `compare_and_set`, but compares the version from `get_with_version`
+    /// instead of the value, so the value does not have to be read again.
+    pub fn compare_and_set_version(
+        &mut self,
+        key: &[u8],
+        expected: Option<Version>,
+        value: &[u8],
+    ) -> Result<bool> {
+        let current = self.live_entry(key).copied();
+        if current.map(Version) != expected {
+            return Ok(false);
+        }
+        let expires_at = current.and_then(|entry| entry.expires_at);
+        self.write(key, value, false, expires_at)?;
+        Ok(true)
+    }
+
+    /// Deletes `key` only if its current value is `expected`. Returns whether it was
+    /// deleted.
+    pub fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        if self.read(key)?.as_deref() != Some(expected) {
+            return Ok(false);
+        }
+        self.delete_key(key)?;
+        Ok(true)
+    }
+}
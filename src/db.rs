@@ -0,0 +1,240 @@
+use std::{fs::File, ops::Bound, path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    compression::CompressionStats,
+    conditional::Version,
+    durability::WriteOptions,
+    expiry::{ExpiryWorker, Ttl},
+    handle::StorageHandle,
+    memory::{MemFile, MemSegmentDir},
+    merge::MergeStats,
+    scan::Cursor,
+    segment::{FsSegmentDir, SegmentDir},
+    snapshot::Snapshot,
+    transaction::Transaction,
+    FileIO, Result, SStStorage, StorageOptions, TornTail, WriteBatch,
+};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{UringFile, UringSegmentDir};
+
+/// How often the background thread checks for keys that have expired.
+const EXPIRY_TICK: Duration = Duration::from_secs(1);
+
+/// A database stored in a directory of segment files, on disk unless it was opened
+/// with `in_memory`.
+///
+/// It can be shared between threads: reads run concurrently and writes one at a
+/// time. Keys that expire are dropped in the background.
+pub struct Db<T: FileIO = File> {
+    storage: StorageHandle<T>,
+    torn_tail: Option<TornTail>,
+    expiry_worker: ExpiryWorker,
+}
+
+impl Db {
+    /// Opens the database in `path`, creating the directory if it does not exist, and
+    /// loads its keydir.
+    pub fn open(path: impl AsRef<Path>, options: StorageOptions) -> Result<Self> {
+        let dir = FsSegmentDir::new(path.as_ref())?;
+        Db::open_dir(Arc::new(dir), options)
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Db<UringFile> {
+    /// Like `open`, but every segment is read and written through io_uring, or with
+    /// blocking calls if the kernel does not allow it.
+    pub fn open_uring(path: impl AsRef<Path>, options: StorageOptions) -> Result<Self> {
+        let dir = UringSegmentDir::new(path.as_ref())?;
+        Db::open_dir(Arc::new(dir), options)
+    }
+}
+
+impl Db<MemFile> {
+    /// Opens an empty database that is kept in memory and is gone once it is dropped.
+    pub fn in_memory(options: StorageOptions) -> Result<Self> {
+        Db::open_dir(Arc::new(MemSegmentDir::new()), options)
+    }
+}
+
+impl<T: FileIO> Db<T> {
+    /// Opens the database in `dir` and loads its keydir.
+    pub fn open_dir(dir: Arc<dyn SegmentDir<T>>, options: StorageOptions) -> Result<Self>
+    where
+        T: Send + Sync + 'static,
+    {
+        let mut storage = SStStorage::open(dir, options)?;
+        let torn_tail = storage.load_db_from_disk()?;
+        let storage = StorageHandle::new(storage);
+        let expiry_worker = ExpiryWorker::start(storage.clone(), EXPIRY_TICK);
+        Ok(Db {
+            storage,
+            torn_tail,
+            expiry_worker,
+        })
+    }
+
+    /// The end of the active segment that was dropped when the database was opened,
+    /// if `RecoveryPolicy::Truncate` had to drop one.
+    pub fn torn_tail(&self) -> Option<&TornTail> {
+        self.torn_tail.as_ref()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.storage.read(key)
+    }
+
+    /// Reads several keys at once, returning their values in the same order. On a
+    /// backend that can, the reads are submitted together.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.storage.get_many(keys)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.storage.write(key, value, false, None)
+    }
+
+    /// Like `put`, but the key expires once `ttl` has passed.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.storage.put_with_ttl(key, value, ttl)
+    }
+
+    /// Like `put`, but `options` decide whether this write is synced, whatever the
+    /// sync policy.
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: WriteOptions) -> Result<()> {
+        self.storage.put_with_options(key, value, options)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.storage.delete_key(key)
+    }
+
+    /// Like `delete`, but `options` decide whether the tombstone is synced, whatever the
+    /// sync policy.
+    pub fn delete_with_options(&self, key: &[u8], options: WriteOptions) -> Result<()> {
+        self.storage.delete_with_options(key, options)
+    }
+
+    /// Applies every put and delete in `batch` at once. After a crash, either all of
+    /// them are there or none are.
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.storage.write_batch(batch)
+    }
+
+    /// Like `write_batch`, but `options` decide whether the batch is synced, whatever the
+    /// sync policy.
+    pub fn write_batch_with_options(
+        &self,
+        batch: &WriteBatch,
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.storage.write_batch_with_options(batch, options)
+    }
+
+    /// Freezes a view of the database. Reads through it are repeatable while writes,
+    /// expiry and merges carry on.
+    pub fn snapshot(&self) -> Result<Snapshot<T>> {
+        self.storage.snapshot()
+    }
+
+    /// Starts a read-modify-write over several keys, which commits only if none of
+    /// the keys it read have changed in the meantime.
+    pub fn transaction(&self) -> Transaction<T> {
+        self.storage.transaction()
+    }
+
+    /// Like `get`, but also returns the version of the value for
+    /// `compare_and_set_version`.
+    pub fn get_with_version(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Version)>> {
+        self.storage.get_with_version(key)
+    }
+
+    /// Writes `value` only if `key` does not exist. Returns whether it was written.
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.storage.put_if_absent(key, value)
+    }
+
+    /// Writes `value` only if `key` exists. Returns whether it was written. The key
+    /// keeps its expiry.
+    pub fn update_if_present(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.storage.update_if_present(key, value)
+    }
+
+    /// Writes `value` only if the current value of `key` is `expected`, or if the key
+    /// does not exist when `expected` is `None`. Returns whether it was written. The
+    /// key keeps its expiry.
+    pub fn compare_and_set(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<bool> {
+        self.storage.compare_and_set(key, expected, value)
+    }
+
+    /// Like `compare_and_set`, against the version `get_with_version` returned.
+    pub fn compare_and_set_version(
+        &self,
+        key: &[u8],
+        expected: Option<Version>,
+        value: &[u8],
+    ) -> Result<bool> {
+        self.storage.compare_and_set_version(key, expected, value)
+    }
+
+    /// Deletes `key` only if its current value is `expected`. Returns whether it was
+    /// deleted.
+    pub fn delete_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        self.storage.delete_if_equals(key, expected)
+    }
+
+    /// Gives an existing key a new expiry, `ttl` from now. Returns `false` if there
+    /// is no such key.
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        self.storage.expire(key, ttl)
+    }
+
+    /// Removes the expiry of a key. Returns `false` if there is no such key or it
+    /// had no expiry.
+    pub fn persist(&self, key: &[u8]) -> Result<bool> {
+        self.storage.persist(key)
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Result<Ttl> {
+        self.storage.ttl(key)
+    }
+
+    /// Iterates over the live keys between `start` and `end`, in ascending order or
+    /// descending after `rev`. Values are read as the iterator reaches them.
+    pub fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Cursor<T> {
+        self.storage.scan(start, end)
+    }
+
+    /// Like `scan`, over the live keys that start with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Cursor<T> {
+        self.storage.scan_prefix(prefix)
+    }
+
+    /// How well the records written since the database was opened compressed.
+    pub fn compression_stats(&self) -> Result<CompressionStats> {
+        self.storage.compression_stats()
+    }
+
+    /// Rewrites the sealed segments without their overwritten, deleted and expired
+    /// records. Reads and writes carry on while it runs.
+    pub fn merge(&self) -> Result<MergeStats> {
+        self.storage.merge()
+    }
+
+    /// Stops the background expiry and syncs everything written so far, whatever
+    /// the sync policy. A database that is only dropped leaves that to the policy.
+    pub fn close(self) -> Result<()> {
+        let Db {
+            storage,
+            expiry_worker,
+            ..
+        } = self;
+        drop(expiry_worker);
+        storage.sync()
+    }
+}
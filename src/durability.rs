@@ -0,0 +1,108 @@
+use std::{
+    str::FromStr,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::FileIO;
+
+/// When appended records are flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync after every write. Nothing acknowledged is ever lost.
+    Always,
+    /// Sync from a background thread every this many milliseconds, which must be at
+    /// least 1.
+    EveryMillis(u64),
+    /// Sync once this many bytes have been appended since the last sync.
+    EveryBytes(u64),
+    /// Leave it to the operating system.
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// Parses `always`, `never`, `every-ms:<millis>` or `every-bytes:<bytes>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_amount = |amount: &str| {
+            amount
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid sync policy {:?}: {}", s, e))
+        };
+        match s.split_once(':') {
+            None if s == "always" => Ok(SyncPolicy::Always),
+            None if s == "never" => Ok(SyncPolicy::Never),
+            Some(("every-ms", millis)) => match parse_amount(millis)? {
+                0 => Err(format!(
+                    "Invalid sync policy {:?}: the interval must be at least 1 ms",
+                    s
+                )),
+                millis => Ok(SyncPolicy::EveryMillis(millis)),
+            },
+            Some(("every-bytes", bytes)) => Ok(SyncPolicy::EveryBytes(parse_amount(bytes)?)),
+            _ => Err(format!("Unknown sync policy {:?}", s)),
+        }
+    }
+}
+
+/// Per-write settings that override the ones the storage was opened with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriteOptions {
+    /// `Some(true)` syncs this write whatever the policy, `Some(false)` never syncs it.
+    pub sync: Option<bool>,
+}
+
+/// Background thread behind `SyncPolicy::EveryMillis`. It syncs its own handle to the
+/// active segment, and is handed a new one whenever the active segment rolls over.
+pub struct Flusher<T> {
+    sender: Option<Sender<T>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<T: FileIO + Send + 'static> Flusher<T> {
+    pub fn start(mut file: T, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<T>();
+        let handle = thread::spawn(move || loop {
+            match receiver.recv_timeout(interval) {
+                Ok(next) => {
+                    // The old segment was synced when it was sealed.
+                    file = next;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = file.sync() {
+                        log::error!("Background sync failed: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = file.sync();
+                    break;
+                }
+            }
+        });
+        Flusher {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl<T> Flusher<T> {
+    /// Points the thread at the new active segment.
+    pub fn rotate(&self, file: T) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(file);
+        }
+    }
+}
+
+impl<T> Drop for Flusher<T> {
+    fn drop(&mut self) {
+        // Closing the channel makes the thread sync one last time and exit.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
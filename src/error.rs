@@ -0,0 +1,168 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::FORMAT_VERSION;
+
+/// Everything that can go wrong reading or writing the database.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Data on disk does not match its checksum. `expected` is the checksum stored
+    /// with the data and `actual` the one computed from it.
+    ///
+    /// `segment` is `None` when the data was parsed on its own rather than read out of
+    /// a segment, in which case `offset` is relative to where parsing started.
+    Corruption {
+        segment: Option<u32>,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// Data on disk cannot be read as a record, though no checksum says so: it has flags
+    /// this build does not know, a length that does not fit, a value that does not
+    /// decompress, or it is cut short where that cannot be a torn append. `segment` and
+    /// `offset` are as for `Corruption`.
+    InvalidRecord {
+        segment: Option<u32>,
+        offset: u64,
+        reason: String,
+    },
+    /// The key is longer than the record format can hold.
+    KeyTooLarge { len: usize, max: usize },
+    /// The value is longer than the record format can hold.
+    ValueTooLarge { len: usize, max: usize },
+    /// Another process has the data directory open.
+    Locked { path: PathBuf },
+    /// A segment was written in a format version this build cannot read.
+    UnsupportedVersion { segment: Option<u32>, version: u8 },
+    /// The key exists, but its expiry has passed.
+    Expired,
+    /// A transaction could not commit because `key`, which it read, was written,
+    /// deleted or expired by someone else before it committed.
+    Conflict { key: Vec<u8> },
+    Io(io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Places an error found while parsing data at `offset` in a segment, or in some
+    /// other file when `segment` is `None`.
+    pub(crate) fn at(self, segment: Option<u32>, offset: u64) -> Self {
+        match self {
+            Error::Corruption {
+                segment: None,
+                offset: relative,
+                expected,
+                actual,
+            } => Error::Corruption {
+                segment,
+                offset: offset + relative,
+                expected,
+                actual,
+            },
+            Error::InvalidRecord {
+                segment: None,
+                offset: relative,
+                reason,
+            } => Error::InvalidRecord {
+                segment,
+                offset: offset + relative,
+                reason,
+            },
+            Error::UnsupportedVersion {
+                segment: None,
+                version,
+            } => Error::UnsupportedVersion { segment, version },
+            e => e,
+        }
+    }
+
+    /// A record that cannot be read, at the start of the data that was parsed.
+    pub(crate) fn invalid_record(reason: impl Into<String>) -> Self {
+        Error::InvalidRecord {
+            segment: None,
+            offset: 0,
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether this is an I/O error of the given kind.
+    pub(crate) fn is_io(&self, kind: io::ErrorKind) -> bool {
+        matches!(self, Error::Io(e) if e.kind() == kind)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Corruption {
+                segment,
+                offset,
+                expected,
+                actual,
+            } => {
+                write!(f, "Checksum mismatch")?;
+                if let Some(segment) = segment {
+                    write!(f, " in segment {}", segment)?;
+                }
+                write!(
+                    f,
+                    " at offset {}: stored {:#010x}, computed {:#010x}",
+                    offset, expected, actual
+                )
+            }
+            Error::InvalidRecord {
+                segment,
+                offset,
+                reason,
+            } => {
+                write!(f, "Invalid record")?;
+                if let Some(segment) = segment {
+                    write!(f, " in segment {}", segment)?;
+                }
+                write!(f, " at offset {}: {}", offset, reason)
+            }
+            Error::KeyTooLarge { len, max } => {
+                write!(f, "Key is {} bytes long, the format allows {}", len, max)
+            }
+            Error::ValueTooLarge { len, max } => {
+                write!(f, "Value is {} bytes long, the format allows {}", len, max)
+            }
+            Error::Locked { path } => {
+                write!(f, "{} is already open in another process", path.display())
+            }
+            Error::UnsupportedVersion { segment, version } => {
+                if let Some(segment) = segment {
+                    write!(f, "Segment {}: ", segment)?;
+                }
+                write!(
+                    f,
+                    "Unsupported segment format version {} (this build reads versions 1 to {})",
+                    version, FORMAT_VERSION
+                )
+            }
+            Error::Expired => write!(f, "Key has expired"),
+            Error::Conflict { key } => write!(
+                f,
+                "Transaction conflict: {:?} was changed by another writer",
+                String::from_utf8_lossy(key)
+            ),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    durability::WriteOptions, handle::StorageHandle, unix_now, FileIO, Record, Result,
+    SStStorage,
+};
+
+/// Keys with an expiry, ordered by when they expire.
+///
+/// Entries are never updated in place. A key that is rewritten or deleted keeps its
+/// old entry, which is recognised as stale and skipped once its deadline comes up.
+#[derive(Default)]
+pub struct ExpiryQueue {
+    heap: BinaryHeap<Reverse<(u64, Vec<u8>)>>,
+}
+
+impl ExpiryQueue {
+    pub fn schedule(&mut self, key: Vec<u8>, expires_at: u64) {
+        self.heap.push(Reverse((expires_at, key)));
+    }
+
+    /// The earliest deadline in the queue, stale or not.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.heap.peek().map(|Reverse((expires_at, _))| *expires_at)
+    }
+
+    /// Removes and returns every entry whose deadline is at or before `now`.
+    pub fn pop_due(&mut self, now: u64) -> Vec<(u64, Vec<u8>)> {
+        let mut due = Vec::new();
+        while self.next_deadline().is_some_and(|expires_at| expires_at <= now) {
+            let Reverse(entry) = self.heap.pop().expect("the queue is not empty");
+            due.push(entry);
+        }
+        due
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+}
+
+/// Background thread that drops keys from the keydir as they expire.
+///
+/// It wakes up every `tick`, and only takes the storage exclusively when the
+/// earliest deadline has passed.
+pub struct ExpiryWorker {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpiryWorker {
+    pub fn start<T>(storage: StorageHandle<T>, tick: Duration) -> Self
+    where
+        T: FileIO + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            // Nothing is ever sent. The channel only closes when the worker is dropped.
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(tick) {
+                if let Err(e) = expire_if_due(&storage) {
+                    log::error!("Background expiry failed: {}", e);
+                }
+            }
+        });
+        ExpiryWorker {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+fn expire_if_due<T: FileIO>(storage: &StorageHandle<T>) -> Result<()> {
+    let now = unix_now();
+    if storage
+        .next_expiry()?
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        storage.cleanup_expired_keys()?;
+    }
+    Ok(())
+}
+
+impl Drop for ExpiryWorker {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// How long a key has left to live.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    /// The key does not exist, or has already expired.
+    Missing,
+    /// The key never expires.
+    Persistent,
+    Expires(Duration),
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Writes `value` for `key`, to expire once `ttl` has passed. Expiry times are
+    /// whole seconds, so `ttl` is rounded up to one.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.write(key, value, false, Some(expires_after(ttl)))
+    }
+
+    /// Gives an existing key a new expiry, `ttl` from now, without rewriting its
+    /// value. Returns `false` if there is no such key.
+    pub fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        self.expire_at(key, expires_after(ttl))
+    }
+
+    /// Like `expire`, but takes the expiry in seconds since the Unix epoch.
+    pub fn expire_at(&mut self, key: &[u8], expires_at: u64) -> Result<bool> {
+        if self.live_entry(key).is_none() {
+            return Ok(false);
+        }
+        self.update_expiry(key, Some(expires_at))?;
+        Ok(true)
+    }
+
+    /// Removes the expiry of a key. Returns `false` if there is no such key or it
+    /// had no expiry.
+    pub fn persist(&mut self, key: &[u8]) -> Result<bool> {
+        match self.live_entry(key) {
+            Some(entry) if entry.expires_at.is_some() => {
+                self.update_expiry(key, None)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Ttl {
+        match self.live_entry(key) {
+            None => Ttl::Missing,
+            Some(entry) => match entry.expires_at {
+                None => Ttl::Persistent,
+                Some(expires_at) => {
+                    Ttl::Expires(Duration::from_secs(expires_at.saturating_sub(self.now())))
+                }
+            },
+        }
+    }
+
+    // Logs the new expiry of a live key and applies it to the keydir.
+    fn update_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let record = Record::expiry_update(key, expires_at);
+        self.append(&record, WriteOptions::default())?;
+        if let Some(entry) = self.index.get_mut(key) {
+            entry.expires_at = expires_at;
+        }
+        if let Some(expires_at) = expires_at {
+            self.schedule_expiry(key.to_vec(), expires_at);
+        }
+        Ok(())
+    }
+}
+
+fn expires_after(ttl: Duration) -> u64 {
+    let seconds = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+    unix_now() + seconds
+}
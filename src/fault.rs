@@ -0,0 +1,335 @@
+use std::{
+    io::{self, SeekFrom},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{segment::SegmentDir, FileIO};
+
+/// Faults to inject into files, shared by every `FaultyFile` and `FaultyDir` made
+/// with it, for testing how the storage copes with failing disks and crashes.
+///
+/// It also remembers every write that has not been synced yet, and what it overwrote,
+/// so that `crash` can put the files back the way a power cut would leave them.
+pub struct FaultInjector<T: FileIO> {
+    state: Mutex<InjectorState<T>>,
+}
+
+struct InjectorState<T: FileIO> {
+    // Bytes that can still be written before writes start failing.
+    write_budget: Option<u64>,
+    fail_syncs: bool,
+    flip_next_read: bool,
+    // Changes to every file since it was last synced, oldest first.
+    unsynced: Vec<Unsynced<T>>,
+    // Counts crashes. Files and directories opened before the last one fail every call.
+    generation: u64,
+}
+
+/// A change to a file that has not been synced, and what it replaced.
+struct Unsynced<T: FileIO> {
+    file: Arc<Mutex<T>>,
+    offset: u64,
+    // Bytes the change added or overwrote from `offset`. Truncating the file adds none.
+    len: u64,
+    // What was in the file from `offset` before the change, up to its old end.
+    overwritten: Vec<u8>,
+    old_len: u64,
+}
+
+/// A file that fails, corrupts reads or loses writes when its `FaultInjector` says so.
+pub struct FaultyFile<T: FileIO> {
+    inner: T,
+    // A second handle to the same file, which a crash rolls unsynced changes back through.
+    // Shared by every clone of this handle.
+    rollback: Arc<Mutex<T>>,
+    injector: Arc<FaultInjector<T>>,
+    generation: u64,
+}
+
+/// A `SegmentDir` whose files are all `FaultyFile`s.
+///
+/// Creating, renaming and deleting files is passed straight through and never lost in
+/// a crash, so only the contents of files are tested.
+pub struct FaultyDir<T: FileIO> {
+    dir: Arc<dyn SegmentDir<T>>,
+    injector: Arc<FaultInjector<T>>,
+    generation: u64,
+}
+
+impl<T: FileIO> Default for FaultInjector<T> {
+    fn default() -> Self {
+        FaultInjector {
+            state: Mutex::new(InjectorState {
+                write_budget: None,
+                fail_syncs: false,
+                flip_next_read: false,
+                unsynced: Vec::new(),
+                generation: 0,
+            }),
+        }
+    }
+}
+
+impl<T: FileIO> FaultInjector<T> {
+    pub fn new() -> Self {
+        FaultInjector::default()
+    }
+
+    // Nothing in the state is left half updated, so a panic elsewhere does not matter.
+    fn state(&self) -> MutexGuard<'_, InjectorState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Makes writes fail once `bytes` more bytes have been written. The write that
+    /// reaches the limit writes what fits and then fails, like a disk that fills up.
+    pub fn fail_writes_after(&self, bytes: u64) {
+        self.state().write_budget = Some(bytes);
+    }
+
+    /// Makes every sync fail, without syncing anything, until it is turned off.
+    pub fn fail_syncs(&self, fail: bool) {
+        self.state().fail_syncs = fail;
+    }
+
+    /// Flips a bit in whatever the next read returns.
+    pub fn flip_next_read(&self) {
+        self.state().flip_next_read = true;
+    }
+
+    /// Simulates a crash: every change that was not synced is lost, and every file and
+    /// directory opened so far fails from now on. The faults are cleared, so storage
+    /// opened again afterwards starts on a healthy disk.
+    pub fn crash(&self) -> io::Result<()> {
+        self.crash_keeping(0)
+    }
+
+    /// Like `crash`, but the first `bytes` bytes of unsynced writes, in the order they
+    /// were made, still reach the disk. That can leave a write cut off halfway.
+    pub fn crash_keeping(&self, bytes: u64) -> io::Result<()> {
+        let mut state = self.state();
+        // Each change that is lost, with how many of its bytes are kept anyway.
+        let mut undo = Vec::new();
+        // `None` once a change has been lost, since everything after it is lost too.
+        let mut remaining = Some(bytes);
+        for change in std::mem::take(&mut state.unsynced) {
+            match remaining {
+                Some(left) if left >= change.len => remaining = Some(left - change.len),
+                Some(left) => {
+                    undo.push((change, left));
+                    remaining = None;
+                }
+                None => undo.push((change, 0)),
+            }
+        }
+        // Undo newest first, so that each change finds the file as it left it.
+        for (change, keep) in undo.into_iter().rev() {
+            let mut file = change.file.lock().unwrap_or_else(|e| e.into_inner());
+            let keep_end = change.offset + keep;
+            let overwritten_from = keep.min(change.overwritten.len() as u64) as usize;
+            if overwritten_from < change.overwritten.len() {
+                file.write_at(&change.overwritten[overwritten_from..], keep_end)?;
+            }
+            file.set_len(change.old_len.max(keep_end))?;
+        }
+        state.write_budget = None;
+        state.fail_syncs = false;
+        state.flip_next_read = false;
+        state.generation += 1;
+        Ok(())
+    }
+
+    /// Wraps a file, which fails from the next crash on.
+    pub fn wrap(self: &Arc<Self>, file: T) -> io::Result<FaultyFile<T>> {
+        Ok(FaultyFile {
+            rollback: Arc::new(Mutex::new(file.try_clone()?)),
+            inner: file,
+            injector: self.clone(),
+            generation: self.state().generation,
+        })
+    }
+
+    fn check_alive(&self, generation: u64) -> io::Result<MutexGuard<'_, InjectorState<T>>> {
+        let state = self.state();
+        if state.generation != generation {
+            return Err(io::Error::other("Injected fault: the process has crashed"));
+        }
+        Ok(state)
+    }
+}
+
+impl<T: FileIO> FaultyFile<T> {
+    // Records what a change from `offset` to `end` is about to replace.
+    fn unsynced(&self, offset: u64, end: u64) -> io::Result<Unsynced<T>> {
+        let old_len = self.inner.len()?;
+        let mut overwritten = vec![0; end.min(old_len).saturating_sub(offset) as usize];
+        if !overwritten.is_empty() {
+            self.inner.read_at(&mut overwritten, offset)?;
+        }
+        Ok(Unsynced {
+            file: self.rollback.clone(),
+            offset,
+            len: end.saturating_sub(offset),
+            overwritten,
+            old_len,
+        })
+    }
+}
+
+impl<T: FileIO> FileIO for FaultyFile<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let offset = self.seek_from(SeekFrom::Current(0))?;
+        self.write_at(buf, offset)?;
+        self.inner.seek_from(SeekFrom::Start(offset + buf.len() as u64))?;
+        Ok(())
+    }
+
+    fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        drop(self.injector.check_alive(self.generation)?);
+        self.inner.seek_from(pos)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let mut state = self.injector.check_alive(self.generation)?;
+        // Only growing the file adds bytes; what shrinking it cut off is kept to restore.
+        let old_len = self.inner.len()?;
+        let mut change = self.unsynced(len.min(old_len), len.max(old_len))?;
+        change.len = len.saturating_sub(old_len);
+        self.inner.set_len(len)?;
+        state.unsynced.push(change);
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut state = self.injector.check_alive(self.generation)?;
+        self.inner.read_at(buf, offset)?;
+        if state.flip_next_read && !buf.is_empty() {
+            state.flip_next_read = false;
+            buf[buf.len() / 2] ^= 1;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut state = self.injector.check_alive(self.generation)?;
+        let allowed = match state.write_budget {
+            Some(budget) => budget.min(buf.len() as u64),
+            None => buf.len() as u64,
+        };
+        let change = self.unsynced(offset, offset + allowed)?;
+        self.inner.write_at(&buf[..allowed as usize], offset)?;
+        state.unsynced.push(change);
+        if let Some(budget) = &mut state.write_budget {
+            *budget -= allowed;
+        }
+        if allowed < buf.len() as u64 {
+            return Err(io::Error::other(format!(
+                "Injected fault: write failed after {} of {} bytes",
+                allowed,
+                buf.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        drop(self.injector.check_alive(self.generation)?);
+        self.inner.len()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.injector.check_alive(self.generation)?;
+        if state.fail_syncs {
+            return Err(io::Error::other("Injected fault: sync failed"));
+        }
+        self.inner.sync()?;
+        state
+            .unsynced
+            .retain(|change| !Arc::ptr_eq(&change.file, &self.rollback));
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(FaultyFile {
+            inner: self.inner.try_clone()?,
+            rollback: self.rollback.clone(),
+            injector: self.injector.clone(),
+            generation: self.generation,
+        })
+    }
+}
+
+impl<T: FileIO> FaultyDir<T> {
+    /// Wraps `dir`, which fails from the next crash on.
+    pub fn new(dir: Arc<dyn SegmentDir<T>>, injector: Arc<FaultInjector<T>>) -> Self {
+        let generation = injector.state().generation;
+        FaultyDir {
+            dir,
+            injector,
+            generation,
+        }
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        drop(self.injector.check_alive(self.generation)?);
+        Ok(())
+    }
+
+    fn wrap(&self, file: T) -> io::Result<FaultyFile<T>> {
+        self.check_alive()?;
+        let mut file = self.injector.wrap(file)?;
+        file.generation = self.generation;
+        Ok(file)
+    }
+}
+
+impl<T: FileIO + Send + 'static> SegmentDir<FaultyFile<T>> for FaultyDir<T> {
+    fn open_segment(&self, id: u32) -> io::Result<FaultyFile<T>> {
+        self.wrap(self.dir.open_segment(id)?)
+    }
+
+    fn segment_ids(&self) -> io::Result<Vec<u32>> {
+        self.check_alive()?;
+        self.dir.segment_ids()
+    }
+
+    fn remove_segment(&self, id: u32) -> io::Result<()> {
+        self.check_alive()?;
+        self.dir.remove_segment(id)
+    }
+
+    fn create_merge_segment(&self, id: u32) -> io::Result<FaultyFile<T>> {
+        self.wrap(self.dir.create_merge_segment(id)?)
+    }
+
+    fn commit_merge_segment(&self, id: u32) -> io::Result<()> {
+        self.check_alive()?;
+        self.dir.commit_merge_segment(id)
+    }
+
+    fn remove_uncommitted_merges(&self) -> io::Result<()> {
+        self.check_alive()?;
+        self.dir.remove_uncommitted_merges()
+    }
+
+    fn create_hint(&self, id: u32) -> io::Result<FaultyFile<T>> {
+        self.wrap(self.dir.create_hint(id)?)
+    }
+
+    fn open_hint(&self, id: u32) -> io::Result<Option<FaultyFile<T>>> {
+        self.check_alive()?;
+        match self.dir.open_hint(id)? {
+            Some(file) => Ok(Some(self.wrap(file)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_hint(&self, id: u32) -> io::Result<()> {
+        self.check_alive()?;
+        self.dir.remove_hint(id)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.check_alive()?;
+        self.dir.sync()
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io,
+    ops::Bound,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use crate::{
+    compression::CompressionStats, conditional::Version, durability::WriteOptions, expiry::Ttl,
+    merge::MergeStats, scan::Cursor, snapshot::Snapshot, transaction::Transaction, Error, FileIO,
+    Result, SStStorage, WriteBatch,
+};
+
+/// A cloneable handle to a storage that can be shared between threads.
+///
+/// Writes, deletes and expiry cleanup take the storage exclusively and run one at a
+/// time. Reads share it, so any number of them run at once and each sees the keydir
+/// either before or after a write, never halfway through one.
+pub struct StorageHandle<T: FileIO> {
+    storage: Arc<RwLock<SStStorage<T>>>,
+    // Held for the whole of a merge so that two merges never overlap.
+    merging: Arc<Mutex<()>>,
+}
+
+impl<T: FileIO> Clone for StorageHandle<T> {
+    fn clone(&self) -> Self {
+        StorageHandle {
+            storage: self.storage.clone(),
+            merging: self.merging.clone(),
+        }
+    }
+}
+
+impl<T: FileIO> StorageHandle<T> {
+    /// Wraps a storage whose keydir has already been loaded.
+    pub fn new(storage: SStStorage<T>) -> Self {
+        StorageHandle {
+            storage: Arc::new(RwLock::new(storage)),
+            merging: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub(crate) fn shared(&self) -> Result<RwLockReadGuard<'_, SStStorage<T>>> {
+        self.storage.read().map_err(|_| poisoned())
+    }
+
+    pub(crate) fn exclusive(&self) -> Result<RwLockWriteGuard<'_, SStStorage<T>>> {
+        self.storage.write().map_err(|_| poisoned())
+    }
+
+    /// Shares the storage until the returned guard is dropped, for reads that span
+    /// more than one call, such as `scan`. Writers wait for it, so keep it short.
+    pub fn view(&self) -> Result<RwLockReadGuard<'_, SStStorage<T>>> {
+        self.shared()
+    }
+
+    pub fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.shared()?.read(key)
+    }
+
+    /// How well the records appended since the storage was opened compressed.
+    pub fn compression_stats(&self) -> Result<CompressionStats> {
+        Ok(self.shared()?.compression_stats())
+    }
+
+    /// Reads several keys at once, returning their values in the same order.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.shared()?.get_many(keys)
+    }
+
+    pub fn write(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        mark_as_deleted: bool,
+        expires_at: Option<u64>,
+    ) -> Result<(), Error> {
+        self.exclusive()?
+            .write(key, value, mark_as_deleted, expires_at)
+    }
+
+    pub fn update(
+        &self,
+        key: &[u8],
+        updated_value: &[u8],
+        mark_as_deleted: bool,
+        expires_at: Option<u64>,
+    ) -> Result<(), Error> {
+        self.exclusive()?
+            .update(key, updated_value, mark_as_deleted, expires_at)
+    }
+
+    pub fn delete_key(&self, key: &[u8]) -> Result<(), Error> {
+        self.exclusive()?.delete_key(key)
+    }
+
+    /// Like `write` for a value with no expiry, with `options` in place of the ones the
+    /// storage was opened with.
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: WriteOptions) -> Result<()> {
+        self.exclusive()?
+            .write_with_options(key, value, false, None, options)
+    }
+
+    /// Like `delete_key`, with `options` in place of the ones the storage was opened with.
+    pub fn delete_with_options(&self, key: &[u8], options: WriteOptions) -> Result<()> {
+        self.exclusive()?.delete_key_with_options(key, options)
+    }
+
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.exclusive()?.write_batch(batch)
+    }
+
+    /// Like `write_batch`, with `options` in place of the ones the storage was opened with.
+    pub fn write_batch_with_options(
+        &self,
+        batch: &WriteBatch,
+        options: WriteOptions,
+    ) -> Result<()> {
+        self.exclusive()?.write_batch_with_options(batch, options)
+    }
+
+    pub fn get_with_version(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Version)>> {
+        self.shared()?.get_with_version(key)
+    }
+
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.exclusive()?.put_if_absent(key, value)
+    }
+
+    pub fn update_if_present(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.exclusive()?.update_if_present(key, value)
+    }
+
+    pub fn compare_and_set(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<bool> {
+        self.exclusive()?.compare_and_set(key, expected, value)
+    }
+
+    pub fn compare_and_set_version(
+        &self,
+        key: &[u8],
+        expected: Option<Version>,
+        value: &[u8],
+    ) -> Result<bool> {
+        self.exclusive()?
+            .compare_and_set_version(key, expected, value)
+    }
+
+    pub fn delete_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        self.exclusive()?.delete_if_equals(key, expected)
+    }
+
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.exclusive()?.put_with_ttl(key, value, ttl)
+    }
+
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        self.exclusive()?.expire(key, ttl)
+    }
+
+    pub fn persist(&self, key: &[u8]) -> Result<bool> {
+        self.exclusive()?.persist(key)
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Result<Ttl> {
+        Ok(self.shared()?.ttl(key))
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.exclusive()?.sync()
+    }
+
+    /// Drops expired keys from the keydir and returns how many there were.
+    pub fn cleanup_expired_keys(&self) -> Result<usize> {
+        Ok(self.exclusive()?.cleanup_expired_keys())
+    }
+
+    /// When the next key is due to expire, if any key has an expiry.
+    pub fn next_expiry(&self) -> Result<Option<u64>> {
+        Ok(self.shared()?.expiry.next_deadline())
+    }
+
+    /// Takes a snapshot. The storage is only shared while the keydir is copied.
+    pub fn snapshot(&self) -> Result<Snapshot<T>> {
+        self.shared()?.snapshot()
+    }
+
+    /// Starts a transaction. It holds on to a clone of the handle, not the storage.
+    pub fn transaction(&self) -> Transaction<T> {
+        Transaction::new(self.clone())
+    }
+
+    /// Iterates over the live keys between `start` and `end` without holding on to
+    /// the storage in between steps.
+    pub fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Cursor<T> {
+        Cursor::new(self.clone(), start, end)
+    }
+
+    /// Like `scan`, over the live keys that start with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Cursor<T> {
+        Cursor::with_prefix(self.clone(), prefix)
+    }
+
+    /// Merges the sealed segments. The storage is only locked while the merge starts
+    /// and while its result is swapped in; reads and writes carry on while records
+    /// are copied.
+    pub fn merge(&self) -> Result<MergeStats> {
+        let _merging = self.merging.lock().map_err(|_| poisoned())?;
+        let plan = match self.exclusive()?.plan_merge()? {
+            Some(plan) => plan,
+            None => return Ok(MergeStats::default()),
+        };
+        let output = plan.copy()?;
+        self.exclusive()?.install_merge(output)
+    }
+}
+
+fn poisoned() -> Error {
+    io::Error::other("Storage lock poisoned: a thread panicked while holding it").into()
+}
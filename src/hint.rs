@@ -0,0 +1,199 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+};
+
+use crate::{unix_now, FileIO, KeyDirEntry, SStStorage};
+
+const HINT_MAGIC: &[u8; 4] = b"BCHT";
+const HINT_VERSION: u8 = 1;
+
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_HAS_WRITE_TIME: u8 = 1 << 1;
+const FLAG_HAS_EXPIRY: u8 = 1 << 2;
+const FLAG_EXPIRY_UPDATE: u8 = 1 << 3;
+
+/// One record of a sealed segment, minus its value.
+///
+/// A hint file lists these in the same order as the records in the segment, so
+/// replaying it rebuilds the keydir exactly like scanning the segment would.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+    pub write_time: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub tombstone: bool,
+    pub expiry_update: bool,
+}
+
+impl HintEntry {
+    /// Applies the record this entry describes to a keydir being rebuilt, oldest
+    /// record first. `now` decides which records have expired.
+    pub(crate) fn apply(self, file_id: u32, index: &mut BTreeMap<Vec<u8>, KeyDirEntry>, now: u64) {
+        // An expired record hides older values for its key just like a tombstone.
+        let expired = self.expires_at.is_some_and(|expires_at| expires_at <= now);
+        if self.expiry_update {
+            // The value stays where it is. Only its expiry changes, if it still exists.
+            if expired {
+                index.remove(&self.key);
+            } else if let Some(entry) = index.get_mut(&self.key) {
+                entry.expires_at = self.expires_at;
+            }
+        } else if self.tombstone || expired {
+            index.remove(&self.key);
+        } else {
+            index.insert(
+                self.key,
+                KeyDirEntry {
+                    file_id,
+                    offset: self.offset,
+                    length: self.length,
+                    write_time: self.write_time,
+                    expires_at: self.expires_at,
+                },
+            );
+        }
+    }
+}
+
+/// Serializes hint entries into the contents of a hint file.
+///
+/// Layout: magic, version, then per entry `flags (u8) | key_len (u32) | offset (u64) |
+/// length (u64) | [write time (u64)] | [expires at (u64)] | key`, and finally a CRC32 of everything before it.
+pub fn encode_hints(entries: &[HintEntry]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(HINT_MAGIC);
+    buffer.push(HINT_VERSION);
+    for entry in entries {
+        let mut flags = 0;
+        if entry.tombstone {
+            flags |= FLAG_TOMBSTONE;
+        }
+        if entry.write_time.is_some() {
+            flags |= FLAG_HAS_WRITE_TIME;
+        }
+        if entry.expires_at.is_some() {
+            flags |= FLAG_HAS_EXPIRY;
+        }
+        if entry.expiry_update {
+            flags |= FLAG_EXPIRY_UPDATE;
+        }
+        buffer.push(flags);
+        buffer.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&entry.offset.to_le_bytes());
+        buffer.extend_from_slice(&entry.length.to_le_bytes());
+        for timestamp in [entry.write_time, entry.expires_at].into_iter().flatten() {
+            buffer.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        buffer.extend_from_slice(&entry.key);
+    }
+    let checksum = crc32fast::hash(&buffer);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+    buffer
+}
+
+/// Parses the contents of a hint file, rejecting it if the checksum does not match.
+pub fn decode_hints(buffer: &[u8]) -> io::Result<Vec<HintEntry>> {
+    if buffer.len() < HINT_MAGIC.len() + 1 + 4 {
+        return Err(invalid_hint("Hint file is truncated"));
+    }
+    let (body, checksum_buffer) = buffer.split_at(buffer.len() - 4);
+    let checksum_from_file = u32::from_le_bytes(checksum_buffer.try_into().unwrap());
+    if crc32fast::hash(body) != checksum_from_file {
+        return Err(invalid_hint("Hint file checksum mismatch"));
+    }
+    if &body[..HINT_MAGIC.len()] != HINT_MAGIC || body[HINT_MAGIC.len()] != HINT_VERSION {
+        return Err(invalid_hint("Not a hint file this version can read"));
+    }
+
+    let mut cursor = io::Cursor::new(&body[HINT_MAGIC.len() + 1..]);
+    let mut entries = Vec::new();
+    while (cursor.position() as usize) < cursor.get_ref().len() {
+        let mut flags = [0u8; 1];
+        cursor.read_exact(&mut flags)?;
+        let key_len = read_u32(&mut cursor)? as usize;
+        let offset = read_u64(&mut cursor)?;
+        let length = read_u64(&mut cursor)?;
+        let write_time = if flags[0] & FLAG_HAS_WRITE_TIME != 0 {
+            Some(read_u64(&mut cursor)?)
+        } else {
+            None
+        };
+        let expires_at = if flags[0] & FLAG_HAS_EXPIRY != 0 {
+            Some(read_u64(&mut cursor)?)
+        } else {
+            None
+        };
+        let mut key = vec![0; key_len];
+        cursor.read_exact(&mut key)?;
+        entries.push(HintEntry {
+            key,
+            offset,
+            length,
+            write_time,
+            expires_at,
+            tombstone: flags[0] & FLAG_TOMBSTONE != 0,
+            expiry_update: flags[0] & FLAG_EXPIRY_UPDATE != 0,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+fn invalid_hint(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Writes the hint file for a sealed segment.
+    pub(crate) fn write_hint_file(&self, file_id: u32, entries: &[HintEntry]) -> io::Result<()> {
+        if let Some(dir) = &self.dir {
+            let mut file = dir.create_hint(file_id)?;
+            file.write(&encode_hints(entries))?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the keydir entries of a sealed segment from its hint file. Returns
+    /// `false` when there is no usable hint file and the segment has to be scanned.
+    pub(crate) fn load_hint_file(&mut self, file_id: u32) -> io::Result<bool> {
+        let file = match &self.dir {
+            Some(dir) => match dir.open_hint(file_id)? {
+                Some(file) => file,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        let mut buffer = vec![0; file.len()? as usize];
+        file.read_at(&mut buffer, 0)?;
+
+        let entries = match decode_hints(&buffer) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!(
+                    "Ignoring hint file for segment {} and scanning it instead: {}",
+                    file_id, e
+                );
+                return Ok(false);
+            }
+        };
+        let now = unix_now();
+        for entry in entries {
+            entry.apply(file_id, &mut self.index, now);
+        }
+        Ok(true)
+    }
+}
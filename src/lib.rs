@@ -1,9 +1,63 @@
-use std::io::{self, Read};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use dance_of_bytes::KeyValue;
+use durability::Flusher;
+use expiry::ExpiryQueue;
+use hint::HintEntry;
+use recovery::record_follows;
+use snapshot::SnapshotRegistry;
 
-// Improved parse_key_value function
-pub fn parse_key_value_from_buffer(buffer: &[u8]) -> io::Result<KeyValue> {
+pub use batch::WriteBatch;
+pub use compression::{Codec, Compression, CompressionStats};
+pub use conditional::Version;
+pub use db::Db;
+pub use durability::{SyncPolicy, WriteOptions};
+pub use error::{Error, Result};
+pub use expiry::{ExpiryWorker, Ttl};
+pub use fault::{FaultInjector, FaultyDir, FaultyFile};
+pub use handle::StorageHandle;
+pub use memory::{MemFile, MemSegmentDir};
+pub use merge::MergeStats;
+pub use mmap::{ReadPath, SegmentMap};
+pub use scan::{Cursor, Keys, Scan};
+pub use segment::{FsSegmentDir, SegmentDir};
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::{UringFile, UringSegmentDir};
+
+mod batch;
+mod compression;
+mod conditional;
+mod db;
+mod durability;
+mod error;
+mod expiry;
+mod fault;
+mod handle;
+mod hint;
+mod lib_test;
+mod memory;
+mod merge;
+mod mmap;
+mod recovery;
+mod scan;
+mod segment;
+mod snapshot;
+mod transaction;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
+pub fn parse_key_value_from_buffer(buffer: &[u8]) -> Result<KeyValue> {
     let mut cursor = std::io::Cursor::new(buffer);
 
     // Read key length (u8)
@@ -49,17 +103,18 @@ pub fn parse_key_value_from_buffer(buffer: &[u8]) -> io::Result<KeyValue> {
     // Calculate the checksum
     let calculated_checksum = kv.calculate_checksum();
     if calculated_checksum != checksum_from_file {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Checksum mismatch",
-        ));
+        return Err(Error::Corruption {
+            segment: None,
+            offset: 0,
+            expected: checksum_from_file,
+            actual: calculated_checksum,
+        });
     }
-    println!("Calculated checksum {} checksum_from_file {}", calculated_checksum, checksum_from_file);
     kv.checksum = calculated_checksum;
     Ok(kv)
 }
 
-pub fn parse_key_value_from_reader<R: Read>(reader: &mut R) -> io::Result<KeyValue> {
+pub fn parse_key_value_from_reader<R: Read>(reader: &mut R) -> Result<KeyValue> {
     // Read key length (u8)
     let mut key_len_buf = [0u8; 1];
     reader.read_exact(&mut key_len_buf)?;
@@ -103,11 +158,1599 @@ pub fn parse_key_value_from_reader<R: Read>(reader: &mut R) -> io::Result<KeyVal
     // Calculate the checksum
     let calculated_checksum = kv.calculate_checksum();
     if calculated_checksum != checksum_from_file {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Checksum mismatch",
-        ));
+        return Err(Error::Corruption {
+            segment: None,
+            offset: 0,
+            expected: checksum_from_file,
+            actual: calculated_checksum,
+        });
     }
     kv.checksum = calculated_checksum;
     Ok(kv)
 }
+
+/// The first bytes of every segment that has a header. Segments that do not start
+/// with it are legacy segments holding v1 records back to back.
+pub const SEGMENT_MAGIC: &[u8; 4] = b"BCSK";
+/// The segment format version written by this build.
+pub const FORMAT_VERSION: u8 = 2;
+/// Size of a segment header in the current format version.
+pub const SEGMENT_HEADER_LEN: usize = 18;
+
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_HAS_WRITE_TIME: u8 = 1 << 1;
+const FLAG_HAS_EXPIRY: u8 = 1 << 2;
+const FLAG_EXPIRY_UPDATE: u8 = 1 << 3;
+const FLAG_BATCH_BEGIN: u8 = 1 << 4;
+const FLAG_BATCH_COMMIT: u8 = 1 << 5;
+const FLAG_LZ4: u8 = 1 << 6;
+const FLAG_ZSTD: u8 = 1 << 7;
+
+// A u32 never takes more than 5 bytes as a varint.
+const MAX_VARINT_LEN: usize = 5;
+
+/// How the records in a segment are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// `key_len (u8) | value_len (u8) | key | value | timestamp (u64) | tombstone (u8) |
+    /// checksum (u32)`, as written by `KeyValue::to_buffer`.
+    V1,
+    /// `flags (u8) | key_len (varint) | value_len (varint) | [write time (u64)] |
+    /// [expires at (u64)] | key | value | crc32 (u32)`. The value is stored compressed
+    /// if the flags name a codec, and `value_len` is its compressed length.
+    V2,
+}
+
+/// One entry in the log, as this crate reads and writes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// When the record was written, in seconds since the Unix epoch.
+    pub write_time: Option<u64>,
+    /// When the key stops being readable, in seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+    pub tombstone: bool,
+    /// Replaces the expiry of the key's current value with `expires_at`, and has no
+    /// value of its own.
+    pub expiry_update: bool,
+    /// Set on the records that open and close a write batch, which have no key or
+    /// value of their own.
+    pub batch_marker: Option<BatchMarker>,
+}
+
+/// The records a write batch is framed by in the log. The records between them only
+/// count once the commit marker is there too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMarker {
+    Begin,
+    Commit,
+}
+
+impl Record {
+    /// A record written now that never expires.
+    pub fn new(key: &[u8], value: &[u8], tombstone: bool) -> Self {
+        Record {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            write_time: Some(chrono::Utc::now().timestamp() as u64),
+            expires_at: None,
+            tombstone,
+            expiry_update: false,
+            batch_marker: None,
+        }
+    }
+
+    /// A record that opens or closes a write batch.
+    pub fn batch_marker(marker: BatchMarker) -> Self {
+        Record {
+            batch_marker: Some(marker),
+            ..Record::new(&[], &[], false)
+        }
+    }
+
+    /// A record that gives the key's current value a new expiry, or none at all.
+    pub fn expiry_update(key: &[u8], expires_at: Option<u64>) -> Self {
+        Record {
+            expires_at,
+            expiry_update: true,
+            ..Record::new(key, &[], false)
+        }
+    }
+
+    /// Whether the record has expired by `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl From<KeyValue> for Record {
+    /// V1 records have a single timestamp, which `KeyValue::new` sets to the time
+    /// of the write. They never expire.
+    fn from(kv: KeyValue) -> Self {
+        Record {
+            key: kv.key,
+            value: kv.value,
+            write_time: kv.timestamp,
+            expires_at: None,
+            tombstone: kv.tombstone,
+            expiry_update: false,
+            batch_marker: None,
+        }
+    }
+}
+
+/// The checksum that protects each record in a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// `KeyValue::calculate_checksum`, used by v1 records.
+    Legacy = 0,
+    Crc32 = 1,
+}
+
+impl ChecksumAlgorithm {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ChecksumAlgorithm::Legacy),
+            1 => Some(ChecksumAlgorithm::Crc32),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the contents of a segment. On disk it is laid out as
+/// `magic (4) | version (u8) | checksum algorithm (u8) | created at (u64) | crc32 (u32)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub version: u8,
+    pub checksum: ChecksumAlgorithm,
+    /// Seconds since the Unix epoch. Unknown for legacy segments.
+    pub created_at: Option<u64>,
+}
+
+impl SegmentHeader {
+    /// The header for a segment created now by this build.
+    pub fn new(created_at: u64) -> Self {
+        SegmentHeader {
+            version: FORMAT_VERSION,
+            checksum: ChecksumAlgorithm::Crc32,
+            created_at: Some(created_at),
+        }
+    }
+
+    /// Stands in for the header a legacy segment does not have.
+    pub fn legacy() -> Self {
+        SegmentHeader {
+            version: 1,
+            checksum: ChecksumAlgorithm::Legacy,
+            created_at: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(SEGMENT_HEADER_LEN);
+        buffer.extend_from_slice(SEGMENT_MAGIC);
+        buffer.push(self.version);
+        buffer.push(self.checksum as u8);
+        buffer.extend_from_slice(&self.created_at.unwrap_or(0).to_le_bytes());
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    /// Reads the header from the first bytes of a segment, which may be shorter than a
+    /// header when the segment is. Fails if the segment has a header this build cannot read.
+    pub fn decode(prefix: &[u8]) -> Result<Self> {
+        if prefix.len() < SEGMENT_MAGIC.len() || &prefix[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC {
+            return Ok(SegmentHeader::legacy());
+        }
+        let version = match prefix.get(SEGMENT_MAGIC.len()) {
+            Some(version) => *version,
+            None => return Err(truncated_header()),
+        };
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                segment: None,
+                version,
+            });
+        }
+        if prefix.len() < SEGMENT_HEADER_LEN {
+            return Err(truncated_header());
+        }
+        let (body, checksum_buffer) = prefix[..SEGMENT_HEADER_LEN].split_at(SEGMENT_HEADER_LEN - 4);
+        let checksum_from_file = u32::from_le_bytes(checksum_buffer.try_into().unwrap());
+        let calculated_checksum = crc32fast::hash(body);
+        if calculated_checksum != checksum_from_file {
+            return Err(Error::Corruption {
+                segment: None,
+                offset: 0,
+                expected: checksum_from_file,
+                actual: calculated_checksum,
+            });
+        }
+        let checksum = ChecksumAlgorithm::from_id(body[5])
+            .ok_or_else(|| invalid_header(&format!("Unknown checksum algorithm {}", body[5])))?;
+        Ok(SegmentHeader {
+            version,
+            checksum,
+            created_at: Some(u64::from_le_bytes(body[6..14].try_into().unwrap())),
+        })
+    }
+
+    /// Offset of the first record in the segment.
+    pub fn data_start(&self) -> u64 {
+        match self.version {
+            1 => 0,
+            _ => SEGMENT_HEADER_LEN as u64,
+        }
+    }
+
+    pub fn record_format(&self) -> RecordFormat {
+        match self.version {
+            1 => RecordFormat::V1,
+            _ => RecordFormat::V2,
+        }
+    }
+}
+
+fn invalid_header(message: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+// A crash while a new segment was being created can leave part of its header behind.
+fn truncated_header() -> Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Segment header is truncated").into()
+}
+
+/// Serializes a record. V1 can only hold keys and values of up to 255 bytes, and no
+/// expiry or batch marker.
+pub fn encode_record(record: &Record, format: RecordFormat) -> Result<Vec<u8>> {
+    encode_record_with(record, format, None)
+}
+
+/// Like `encode_record`, but the value is compressed as `compression` says if it is
+/// long enough and gets shorter. V1 records always hold their value as it is.
+pub fn encode_record_with(
+    record: &Record,
+    format: RecordFormat,
+    compression: Option<Compression>,
+) -> Result<Vec<u8>> {
+    match format {
+        RecordFormat::V1 => {
+            check_len(record, u8::MAX as usize)?;
+            if record.expires_at.is_some() || record.expiry_update {
+                return Err(no_expiry_in_format());
+            }
+            if record.batch_marker.is_some() {
+                return Err(no_batches_in_format());
+            }
+            let kv = KeyValue::new(
+                &record.key,
+                &record.value,
+                record.write_time,
+                record.tombstone,
+                0,
+            );
+            Ok(kv.to_buffer())
+        }
+        RecordFormat::V2 => encode_record_v2(record, compression),
+    }
+}
+
+/// Number of bytes `record` takes up on disk in the given format with its value
+/// stored uncompressed. A record read back from disk may have taken up fewer.
+pub fn record_len(record: &Record, format: RecordFormat) -> u64 {
+    match format {
+        // Every field of a v1 record has a fixed size apart from the key and value.
+        RecordFormat::V1 => (2 + record.key.len() + record.value.len() + 8 + 1 + 4) as u64,
+        RecordFormat::V2 => {
+            let timestamps_len = [record.write_time, record.expires_at]
+                .iter()
+                .filter(|timestamp| timestamp.is_some())
+                .count()
+                * 8;
+            (1 + varint_len(record.key.len() as u32)
+                + varint_len(record.value.len() as u32)
+                + timestamps_len
+                + record.key.len()
+                + record.value.len()
+                + 4) as u64
+        }
+    }
+}
+
+pub fn parse_record_from_buffer(buffer: &[u8], format: RecordFormat) -> Result<Record> {
+    parse_record_from_reader(&mut std::io::Cursor::new(buffer), format)
+}
+
+/// The value in the bytes of the record a keydir entry points at.
+fn decode_value(entry: &KeyDirEntry, bytes: &[u8], format: RecordFormat) -> Result<Vec<u8>> {
+    let record = parse_record_from_buffer(bytes, format)
+        .map_err(|e| e.at(Some(entry.file_id), entry.offset))?;
+    Ok(record.value)
+}
+
+pub fn parse_record_from_reader<R: Read>(
+    reader: &mut R,
+    format: RecordFormat,
+) -> Result<Record> {
+    match format {
+        RecordFormat::V1 => parse_key_value_from_reader(reader).map(Record::from),
+        RecordFormat::V2 => parse_record_v2_from_reader(reader),
+    }
+}
+
+/// Reads every record in a segment file, whichever format it is in.
+pub fn read_records_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<Record>> {
+    read_records(&std::fs::read(path)?)
+}
+
+/// Like `read_records_from_file`, for a segment that has already been read into memory.
+pub fn read_records(contents: &[u8]) -> Result<Vec<Record>> {
+    let header = SegmentHeader::decode(contents)?;
+    let format = header.record_format();
+    let mut cursor = std::io::Cursor::new(contents);
+    cursor.set_position(header.data_start());
+
+    let mut records = Vec::new();
+    while (cursor.position() as usize) < contents.len() {
+        let offset = cursor.position();
+        records.push(parse_record_from_reader(&mut cursor, format).map_err(|e| e.at(None, offset))?);
+    }
+    Ok(records)
+}
+
+fn no_expiry_in_format() -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "V1 records cannot hold an expiry",
+    )
+    .into()
+}
+
+fn no_batches_in_format() -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "V1 records cannot hold a write batch marker",
+    )
+    .into()
+}
+
+fn check_len(record: &Record, max: usize) -> Result<()> {
+    if record.key.len() > max {
+        return Err(Error::KeyTooLarge {
+            len: record.key.len(),
+            max,
+        });
+    }
+    if record.value.len() > max {
+        return Err(Error::ValueTooLarge {
+            len: record.value.len(),
+            max,
+        });
+    }
+    Ok(())
+}
+
+fn encode_record_v2(record: &Record, compression: Option<Compression>) -> Result<Vec<u8>> {
+    check_len(record, u32::MAX as usize)?;
+    let compressed = compression.and_then(|compression| {
+        let stored = compression.compress(&record.value)?;
+        Some((compression.codec, stored))
+    });
+    let value = match &compressed {
+        Some((_, stored)) => stored,
+        None => &record.value,
+    };
+    let key_len = record.key.len() as u32;
+    let value_len = value.len() as u32;
+
+    let mut flags = 0;
+    if record.tombstone {
+        flags |= FLAG_TOMBSTONE;
+    }
+    if record.write_time.is_some() {
+        flags |= FLAG_HAS_WRITE_TIME;
+    }
+    if record.expires_at.is_some() {
+        flags |= FLAG_HAS_EXPIRY;
+    }
+    if record.expiry_update {
+        flags |= FLAG_EXPIRY_UPDATE;
+    }
+    match record.batch_marker {
+        Some(BatchMarker::Begin) => flags |= FLAG_BATCH_BEGIN,
+        Some(BatchMarker::Commit) => flags |= FLAG_BATCH_COMMIT,
+        None => {}
+    }
+    match compressed {
+        Some((Codec::Lz4, _)) => flags |= FLAG_LZ4,
+        Some((Codec::Zstd, _)) => flags |= FLAG_ZSTD,
+        None => {}
+    }
+
+    let mut buffer = Vec::with_capacity(record_len(record, RecordFormat::V2) as usize);
+    buffer.push(flags);
+    write_varint(&mut buffer, key_len);
+    write_varint(&mut buffer, value_len);
+    for timestamp in [record.write_time, record.expires_at].into_iter().flatten() {
+        buffer.extend_from_slice(&timestamp.to_le_bytes());
+    }
+    buffer.extend_from_slice(&record.key);
+    buffer.extend_from_slice(value);
+    let checksum = crc32fast::hash(&buffer);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+    Ok(buffer)
+}
+
+/// Gives an encoded v2 record a new expiry. The key and value bytes are kept as they
+/// are, so a compressed value stays compressed with the codec it was written with.
+pub(crate) fn rewrite_expiry_v2(encoded: &[u8], expires_at: Option<u64>) -> Result<Vec<u8>> {
+    let mut cursor = std::io::Cursor::new(encoded);
+    let mut flags_buf = [0u8; 1];
+    cursor.read_exact(&mut flags_buf)?;
+    let flags = flags_buf[0];
+    let mut lengths = Vec::new();
+    read_varint(&mut cursor, &mut lengths)?;
+    read_varint(&mut cursor, &mut lengths)?;
+    let mut timestamps = Vec::new();
+    let write_time =
+        read_timestamp_if(&mut cursor, &mut timestamps, flags & FLAG_HAS_WRITE_TIME != 0)?;
+    read_timestamp_if(&mut cursor, &mut timestamps, flags & FLAG_HAS_EXPIRY != 0)?;
+    let key_and_value = encoded
+        .get(cursor.position() as usize..encoded.len().saturating_sub(4))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Record is cut short"))?;
+
+    let mut buffer = Vec::with_capacity(encoded.len() + 8);
+    buffer.push(match expires_at {
+        Some(_) => flags | FLAG_HAS_EXPIRY,
+        None => flags & !FLAG_HAS_EXPIRY,
+    });
+    buffer.extend_from_slice(&lengths);
+    for timestamp in [write_time, expires_at].into_iter().flatten() {
+        buffer.extend_from_slice(&timestamp.to_le_bytes());
+    }
+    buffer.extend_from_slice(key_and_value);
+    let checksum = crc32fast::hash(&buffer);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+    Ok(buffer)
+}
+
+fn parse_record_v2_from_reader<R: Read>(reader: &mut R) -> Result<Record> {
+    // Everything before the checksum is kept so it can be verified.
+    let mut raw = Vec::new();
+
+    let mut flags_buf = [0u8; 1];
+    reader.read_exact(&mut flags_buf)?;
+    raw.push(flags_buf[0]);
+    let flags = flags_buf[0];
+    if !valid_flags(flags) {
+        return Err(Error::invalid_record(format!(
+            "Unknown record flags {:#04x}",
+            flags
+        )));
+    }
+
+    let key_len = read_varint(reader, &mut raw)? as usize;
+    let value_len = read_varint(reader, &mut raw)? as usize;
+
+    let write_time = read_timestamp_if(reader, &mut raw, flags & FLAG_HAS_WRITE_TIME != 0)?;
+    let expires_at = read_timestamp_if(reader, &mut raw, flags & FLAG_HAS_EXPIRY != 0)?;
+
+    // The lengths are not trusted until the checksum has been checked, so the key and
+    // value are read without allocating their full size up front.
+    let key = read_vec(reader, key_len)?;
+    raw.extend_from_slice(&key);
+    let value = read_vec(reader, value_len)?;
+    raw.extend_from_slice(&value);
+
+    let mut checksum_buffer = [0u8; 4];
+    reader.read_exact(&mut checksum_buffer)?;
+    let checksum_from_file = u32::from_le_bytes(checksum_buffer);
+    let calculated_checksum = crc32fast::hash(&raw);
+    if calculated_checksum != checksum_from_file {
+        return Err(Error::Corruption {
+            segment: None,
+            offset: 0,
+            expected: checksum_from_file,
+            actual: calculated_checksum,
+        });
+    }
+    // Only decompressed once the checksum shows the value is what was written.
+    let codec = if flags & FLAG_LZ4 != 0 {
+        Some(Codec::Lz4)
+    } else if flags & FLAG_ZSTD != 0 {
+        Some(Codec::Zstd)
+    } else {
+        None
+    };
+    let value = match codec {
+        Some(codec) => codec.decompress(&value).map_err(|e| {
+            Error::invalid_record(format!("Value does not decompress as {:?}: {}", codec, e))
+        })?,
+        None => value,
+    };
+
+    Ok(Record {
+        key,
+        value,
+        write_time,
+        expires_at,
+        tombstone: flags & FLAG_TOMBSTONE != 0,
+        expiry_update: flags & FLAG_EXPIRY_UPDATE != 0,
+        batch_marker: if flags & FLAG_BATCH_BEGIN != 0 {
+            Some(BatchMarker::Begin)
+        } else if flags & FLAG_BATCH_COMMIT != 0 {
+            Some(BatchMarker::Commit)
+        } else {
+            None
+        },
+    })
+}
+
+// Whether a v2 record can have these flags: only ones this build knows, and at most one
+// codec.
+fn valid_flags(flags: u8) -> bool {
+    let known_flags = FLAG_TOMBSTONE
+        | FLAG_HAS_WRITE_TIME
+        | FLAG_HAS_EXPIRY
+        | FLAG_EXPIRY_UPDATE
+        | FLAG_BATCH_BEGIN
+        | FLAG_BATCH_COMMIT
+        | FLAG_LZ4
+        | FLAG_ZSTD;
+    flags & !known_flags == 0 && flags & (FLAG_LZ4 | FLAG_ZSTD) != FLAG_LZ4 | FLAG_ZSTD
+}
+
+/// The length the record at the start of `buffer` claims to have, if its flags are
+/// valid and all of it fits in `buffer`. Only the bytes before the key are looked at.
+fn claimed_record_len(buffer: &[u8], format: RecordFormat) -> Option<usize> {
+    let len = match format {
+        RecordFormat::V1 => {
+            // Both lengths, the key and value, the timestamp, the tombstone and the checksum.
+            let (key_len, value_len) = (*buffer.first()?, *buffer.get(1)?);
+            2 + key_len as usize + value_len as usize + 8 + 1 + 4
+        }
+        RecordFormat::V2 => {
+            let flags = *buffer.first()?;
+            if !valid_flags(flags) {
+                return None;
+            }
+            let mut rest = &buffer[1..];
+            let mut raw = Vec::new();
+            let key_len = read_varint(&mut rest, &mut raw).ok()? as usize;
+            let value_len = read_varint(&mut rest, &mut raw).ok()? as usize;
+            let timestamps = [FLAG_HAS_WRITE_TIME, FLAG_HAS_EXPIRY]
+                .iter()
+                .filter(|&&flag| flags & flag != 0)
+                .count();
+            1 + raw.len() + 8 * timestamps + key_len + value_len + 4
+        }
+    };
+    (len <= buffer.len()).then_some(len)
+}
+
+fn read_timestamp_if<R: Read>(
+    reader: &mut R,
+    raw: &mut Vec<u8>,
+    present: bool,
+) -> io::Result<Option<u64>> {
+    if !present {
+        return Ok(None);
+    }
+    let mut timestamp_buffer = [0u8; 8];
+    reader.read_exact(&mut timestamp_buffer)?;
+    raw.extend_from_slice(&timestamp_buffer);
+    Ok(Some(u64::from_le_bytes(timestamp_buffer)))
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn varint_len(value: u32) -> usize {
+    let mut len = 1;
+    let mut value = value >> 7;
+    while value != 0 {
+        len += 1;
+        value >>= 7;
+    }
+    len
+}
+
+fn read_varint<R: Read>(reader: &mut R, raw: &mut Vec<u8>) -> Result<u32> {
+    let mut value: u32 = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        raw.push(byte[0]);
+        // Only the low 4 bits of the fifth byte still fit in a u32.
+        if i == MAX_VARINT_LEN - 1 && byte[0] > 0x0F {
+            break;
+        }
+        value |= ((byte[0] & 0x7F) as u32) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::invalid_record("Length does not fit in a u32"))
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Record is cut short",
+        ));
+    }
+    Ok(buffer)
+}
+
+/// Size at which the active segment is sealed and a new one is started.
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Where the latest record for a key lives on disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KeyDirEntry {
+    file_id: u32,
+    offset: u64,
+    length: u64,
+    write_time: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+impl KeyDirEntry {
+    /// Whether the key has expired by `now`, in seconds since the Unix epoch.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The current time in seconds since the Unix epoch, which is what expiry times use.
+fn unix_now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+/// What `load_db_from_disk` does when the active segment ends in a record that cannot
+/// be read, which is what a crash in the middle of an append leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryPolicy {
+    /// Refuse to open the database.
+    Strict,
+    /// Keep every record before the bad one and cut the segment off there.
+    Truncate,
+}
+
+impl FromStr for RecoveryPolicy {
+    type Err = String;
+
+    /// Parses `strict` or `truncate`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(RecoveryPolicy::Strict),
+            "truncate" => Ok(RecoveryPolicy::Truncate),
+            _ => Err(format!("Unknown recovery policy {:?}", s)),
+        }
+    }
+}
+
+/// The end of the active segment that `RecoveryPolicy::Truncate` dropped.
+#[derive(Debug, PartialEq)]
+pub struct TornTail {
+    pub file_id: u32,
+    pub offset: u64,
+    pub dropped_bytes: u64,
+}
+
+/// Settings chosen when a data directory is opened.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageOptions {
+    /// The active segment is sealed once appending a record would grow it past this size.
+    pub max_segment_size: u64,
+    pub recovery: RecoveryPolicy,
+    /// How often appends are synced to disk. `WriteOptions::sync` overrides it per write.
+    pub sync: SyncPolicy,
+    /// How records are read from sealed segments.
+    pub read_path: ReadPath,
+    /// How values are compressed as they are written. `None` stores them as they are.
+    pub compression: Option<Compression>,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            recovery: RecoveryPolicy::Strict,
+            sync: SyncPolicy::Never,
+            read_path: ReadPath::File,
+            compression: None,
+        }
+    }
+}
+
+/// What was read from a segment, and where reading it stopped early if it did.
+struct SegmentReplay {
+    hints: Vec<HintEntry>,
+    // Offset of the first record that could not be read, and why.
+    failure: Option<(u64, Error)>,
+    // Offset of a write batch that was still open where reading stopped. None of its
+    // records were applied.
+    unfinished_batch: Option<u64>,
+}
+
+/// A Bitcask log: append-only segments on disk, and an in-memory keydir that points
+/// at the latest record for every key.
+pub struct SStStorage<T: FileIO> {
+    index: BTreeMap<Vec<u8>, KeyDirEntry>,
+    // The only segment that is ever appended to.
+    active: T,
+    active_id: u32,
+    // Every record appended to the active segment, written out as its hint file once it is sealed.
+    active_hints: Vec<HintEntry>,
+    // Immutable segments, keyed by file id.
+    sealed: BTreeMap<u32, T>,
+    // Sealed segments mapped into memory under `ReadPath::Mmap`. Shared with snapshots,
+    // so a segment stays mapped until the last reader is done with it.
+    maps: BTreeMap<u32, Arc<SegmentMap>>,
+    // Header of each segment, filled in the first time the segment is used.
+    headers: BTreeMap<u32, SegmentHeader>,
+    // `None` when the storage wraps a single file, in which case it never rolls over.
+    dir: Option<Arc<dyn SegmentDir<T>>>,
+    options: StorageOptions,
+    // Bytes appended to the active segment since it was last synced.
+    unsynced_bytes: u64,
+    // Only running under `SyncPolicy::EveryMillis`.
+    flusher: Option<Flusher<T>>,
+    // Where the next record goes in the active segment. Looked up the first time it is
+    // needed, and kept separately from the file cursor, which reads never move.
+    append_offset: Option<u64>,
+    // Keys in the keydir that have an expiry, soonest first.
+    expiry: ExpiryQueue,
+    // Which segments live snapshots read from. Merges leave those on disk until the
+    // snapshots are dropped.
+    snapshots: Arc<SnapshotRegistry<T>>,
+    // Set on the copy of the storage a snapshot reads from, so that keys expire as of
+    // when the snapshot was taken.
+    frozen_at: Option<u64>,
+    // How well the records appended since the storage was opened compressed.
+    compression_stats: CompressionStats,
+}
+
+/// The file operations the storage is built on, so that it can run on something
+/// other than `std::fs::File`.
+pub trait FileIO {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64>;
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    /// Fills `buf` from `offset` without moving the file cursor.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    /// Writes all of `buf` at `offset` without moving the file cursor.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Fills each buffer from its offset. Backends that can have several reads in
+    /// flight at once submit them together.
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        for (offset, buf) in reads.iter_mut() {
+            self.read_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+    /// Writes all of `buf` at `offset` and then syncs. Backends that can queue the sync
+    /// behind the write submit both together.
+    fn write_at_and_sync(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_at(buf, offset)?;
+        self.sync()
+    }
+    /// The current size of the file.
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Flushes everything written so far to stable storage.
+    fn sync(&mut self) -> io::Result<()>;
+    /// Opens a second handle to the same file.
+    fn try_clone(&self) -> io::Result<Self>
+    where
+        Self: Sized;
+    /// Maps the whole file into memory for reading. Only called on sealed segments,
+    /// which are never written to again. Backends that cannot be mapped return `None`
+    /// and are read with `read_at`.
+    fn map(&self) -> io::Result<Option<SegmentMap>> {
+        Ok(None)
+    }
+}
+
+impl FileIO for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        File::write_all(self, buf)
+    }
+
+    fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        File::seek(self, pos)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        // `seek_read` moves the cursor on Windows, but appends never rely on it.
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    buf = &mut std::mem::take(&mut buf)[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&mut self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_write(self, buf, offset)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        File::try_clone(self)
+    }
+
+    fn map(&self) -> io::Result<Option<SegmentMap>> {
+        mmap::map_file(self).map(Some)
+    }
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Wraps a single file. Everything is appended to it and it is never sealed.
+    pub fn new(file: T) -> Self {
+        SStStorage {
+            index: BTreeMap::new(),
+            active: file,
+            active_id: 0,
+            active_hints: Vec::new(),
+            sealed: BTreeMap::new(),
+            maps: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            dir: None,
+            options: StorageOptions::default(),
+            unsynced_bytes: 0,
+            flusher: None,
+            append_offset: None,
+            expiry: ExpiryQueue::default(),
+            snapshots: Arc::new(SnapshotRegistry::new(None)),
+            frozen_at: None,
+            compression_stats: CompressionStats::default(),
+        }
+    }
+
+    /// Opens every segment in `dir`. The newest segment becomes the active one;
+    /// an empty directory starts out with segment 1.
+    pub fn open(dir: Arc<dyn SegmentDir<T>>, options: StorageOptions) -> Result<Self>
+    where
+        T: Send + 'static,
+    {
+        // The flusher would sync in a busy loop.
+        if options.sync == SyncPolicy::EveryMillis(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SyncPolicy::EveryMillis needs an interval of at least 1 ms",
+            )
+            .into());
+        }
+        // A merge that crashed before committing leaves its inputs untouched.
+        dir.remove_uncommitted_merges()?;
+        let ids = dir.segment_ids()?;
+        let active_id = ids.last().copied().unwrap_or(1);
+
+        let mut sealed = BTreeMap::new();
+        for &id in ids.iter().filter(|&&id| id != active_id) {
+            sealed.insert(id, dir.open_segment(id)?);
+        }
+        let active = dir.open_segment(active_id)?;
+        let flusher = match options.sync {
+            SyncPolicy::EveryMillis(interval) => Some(Flusher::start(
+                active.try_clone()?,
+                Duration::from_millis(interval),
+            )),
+            _ => None,
+        };
+
+        let mut storage = SStStorage {
+            index: BTreeMap::new(),
+            active,
+            active_id,
+            active_hints: Vec::new(),
+            sealed,
+            maps: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            snapshots: Arc::new(SnapshotRegistry::new(Some(dir.clone()))),
+            dir: Some(dir),
+            options,
+            unsynced_bytes: 0,
+            flusher,
+            append_offset: None,
+            expiry: ExpiryQueue::default(),
+            frozen_at: None,
+            compression_stats: CompressionStats::default(),
+        };
+        for &id in ids.iter().filter(|&&id| id != active_id) {
+            storage.map_sealed(id)?;
+        }
+        Ok(storage)
+    }
+
+    fn insert_key(&mut self, key: Vec<u8>, value: KeyDirEntry) {
+        self.index.insert(key, value);
+    }
+
+    fn segment(&self, file_id: u32) -> Result<&T> {
+        if file_id == self.active_id {
+            return Ok(&self.active);
+        }
+        self.sealed.get(&file_id).ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Segment {} is not open", file_id),
+            ))
+        })
+    }
+
+    /// The header of a segment, read and validated the first time it is needed. An
+    /// empty active segment is given a header for the current format version. Sealed
+    /// segments are never written to, since they may be mapped, so an empty one reads
+    /// as a legacy segment with no records.
+    fn segment_header(&mut self, file_id: u32) -> Result<SegmentHeader> {
+        if let Some(header) = self.headers.get(&file_id) {
+            return Ok(*header);
+        }
+        let header = if file_id == self.active_id && self.active.is_empty()? {
+            let header = SegmentHeader::new(unix_now());
+            self.active.write_at(&header.encode(), 0)?;
+            self.append_offset = Some(SEGMENT_HEADER_LEN as u64);
+            header
+        } else {
+            self.read_segment_header(file_id)?
+        };
+        self.headers.insert(file_id, header);
+        Ok(header)
+    }
+
+    /// Like `segment_header`, but works through `&self` because it never writes or
+    /// caches. Any segment a keydir entry points at has a header to read.
+    fn cached_segment_header(&self, file_id: u32) -> Result<SegmentHeader> {
+        match self.headers.get(&file_id) {
+            Some(header) => Ok(*header),
+            None => self.read_segment_header(file_id),
+        }
+    }
+
+    fn read_segment_header(&self, file_id: u32) -> Result<SegmentHeader> {
+        let file = self.segment(file_id)?;
+        let size = file.len()?;
+        let mut prefix = vec![0; size.min(SEGMENT_HEADER_LEN as u64) as usize];
+        file.read_at(&mut prefix, 0)?;
+        SegmentHeader::decode(&prefix).map_err(|e| match e {
+            Error::Io(e) => io::Error::new(e.kind(), format!("Segment {}: {}", file_id, e)).into(),
+            e => e.at(Some(file_id), 0),
+        })
+    }
+
+    /// Reads the raw bytes of the record a keydir entry points at, borrowed from the
+    /// segment's map if it has one.
+    fn read_entry(&self, entry: &KeyDirEntry) -> Result<Cow<'_, [u8]>> {
+        if let Some(bytes) = self.mapped_entry(entry) {
+            return Ok(Cow::Borrowed(bytes));
+        }
+        let mut buffer = vec![0; entry.length as usize];
+        self.segment(entry.file_id)?
+            .read_at(&mut buffer, entry.offset)?;
+        Ok(Cow::Owned(buffer))
+    }
+
+    /// The offset the next record will be appended at in the active segment.
+    fn append_offset(&mut self) -> Result<u64> {
+        match self.append_offset {
+            Some(offset) => Ok(offset),
+            None => {
+                let offset = self.active.len()?;
+                self.append_offset = Some(offset);
+                Ok(offset)
+            }
+        }
+    }
+
+    /// Seals the active segment and starts appending to a new, empty one.
+    pub fn rollover(&mut self) -> Result<()> {
+        self.rollover_to(self.active_id + 1)
+    }
+
+    /// Like `rollover`, but the new active segment gets `next_id`, which must be
+    /// greater than every existing segment id.
+    fn rollover_to(&mut self, next_id: u32) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        // The header goes in while the segment can still be written to.
+        self.segment_header(self.active_id)?;
+        let next = dir.open_segment(next_id)?;
+        if self.options.sync != SyncPolicy::Never {
+            // Whatever the policy, a sealed segment is fully on disk, and so is the
+            // directory entry of the segment that replaces it.
+            self.active.sync()?;
+            self.unsynced_bytes = 0;
+            dir.sync()?;
+        }
+        if let Some(flusher) = &self.flusher {
+            flusher.rotate(next.try_clone()?);
+        }
+        let hints = std::mem::take(&mut self.active_hints);
+        self.write_hint_file(self.active_id, &hints)?;
+        let sealed = std::mem::replace(&mut self.active, next);
+        self.sealed.insert(self.active_id, sealed);
+        self.map_sealed(self.active_id)?;
+        self.active_id = next_id;
+        self.append_offset = None;
+        Ok(())
+    }
+
+    /// Appends a record for `key`. It is stamped with the current time, and stops
+    /// being readable at `expires_at` (seconds since the Unix epoch) if one is given.
+    pub fn write(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        mark_as_deleted: bool,
+        expires_at: Option<u64>,
+    ) -> Result<(), Error> {
+        self.write_with_options(key, value, mark_as_deleted, expires_at, WriteOptions::default())
+    }
+
+    pub fn write_with_options(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        mark_as_deleted: bool,
+        expires_at: Option<u64>,
+        write_options: WriteOptions,
+    ) -> Result<(), Error> {
+        let record = Record {
+            expires_at,
+            ..Record::new(key, value, mark_as_deleted)
+        };
+        let (offset, length) = self.append(&record, write_options)?;
+        // Only update the in-memory index for new or updated keys, not for deletions.
+        if !mark_as_deleted {
+            let entry = KeyDirEntry {
+                file_id: self.active_id,
+                offset,
+                length,
+                write_time: record.write_time,
+                expires_at,
+            };
+            self.insert_key(key.to_vec(), entry);
+            if let Some(expires_at) = expires_at {
+                self.schedule_expiry(key.to_vec(), expires_at);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a record to the active segment, sealing the segment first if the record
+    /// does not fit, and returns the offset and length it was written at. The keydir is
+    /// left for the caller to update.
+    fn append(&mut self, record: &Record, write_options: WriteOptions) -> Result<(u64, u64)> {
+        let placed = self.append_all(std::slice::from_ref(record), write_options)?;
+        Ok(placed[0])
+    }
+
+    /// Like `append`, but for several records written back to back in one go. They
+    /// always end up in the same segment.
+    fn append_all(
+        &mut self,
+        records: &[Record],
+        write_options: WriteOptions,
+    ) -> Result<Vec<(u64, u64)>> {
+        // Legacy segments are only appended to when there is no directory to start a
+        // segment with a header in.
+        let mut header = self.segment_header(self.active_id)?;
+        if header.version != FORMAT_VERSION && self.dir.is_some() {
+            self.rollover()?;
+            header = self.segment_header(self.active_id)?;
+        }
+        let mut buffer = Vec::new();
+        let mut lengths = Vec::with_capacity(records.len());
+        let mut stats = self.compression_stats;
+        for record in records {
+            let encoded =
+                encode_record_with(record, header.record_format(), self.options.compression)?;
+            let raw_len = record_len(record, header.record_format());
+            stats.records += 1;
+            stats.raw_bytes += raw_len;
+            stats.stored_bytes += encoded.len() as u64;
+            // A value is only stored compressed when that makes it shorter.
+            if (encoded.len() as u64) < raw_len {
+                stats.records_compressed += 1;
+            }
+            lengths.push(encoded.len() as u64);
+            buffer.extend_from_slice(&encoded);
+        }
+        let length = buffer.len() as u64;
+        let mut offset = self.append_offset()?;
+        // Seal the active segment if these records would grow it past the limit. An empty
+        // segment always takes them, however large they are.
+        if offset > header.data_start()
+            && offset + length > self.options.max_segment_size
+            && self.dir.is_some()
+        {
+            self.rollover()?;
+            self.segment_header(self.active_id)?;
+            offset = self.append_offset()?;
+        }
+        let sync_now = write_options.sync.unwrap_or(match self.options.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryBytes(threshold) => self.unsynced_bytes + length >= threshold,
+            SyncPolicy::EveryMillis(_) | SyncPolicy::Never => false,
+        });
+        if sync_now {
+            self.active.write_at_and_sync(&buffer, offset)?;
+            self.unsynced_bytes = 0;
+        } else {
+            self.active.write_at(&buffer, offset)?;
+            self.unsynced_bytes += length;
+        }
+        self.append_offset = Some(offset + length);
+        self.compression_stats = stats;
+
+        let mut placed = Vec::with_capacity(records.len());
+        for (record, length) in records.iter().zip(lengths) {
+            placed.push((offset, length));
+            // Batch markers never reach the keydir, so hint files leave them out.
+            if record.batch_marker.is_none() {
+                self.active_hints.push(HintEntry {
+                    key: record.key.clone(),
+                    offset,
+                    length,
+                    write_time: record.write_time,
+                    expires_at: record.expires_at,
+                    tombstone: record.tombstone,
+                    expiry_update: record.expiry_update,
+                });
+            }
+            offset += length;
+        }
+        Ok(placed)
+    }
+
+    /// Flushes everything appended so far to stable storage, whatever the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        self.active.sync()?;
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// The keydir entry for `key`, unless the key is missing or has expired. Expired
+    /// entries stay in the keydir until `cleanup_expired_keys` or a merge drops them.
+    fn live_entry(&self, key: &[u8]) -> Option<&KeyDirEntry> {
+        self.index
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now()))
+    }
+
+    /// The time reads decide expiry by: the current time, or when the snapshot was
+    /// taken for a snapshot's copy of the storage.
+    fn now(&self) -> u64 {
+        self.frozen_at.unwrap_or_else(unix_now)
+    }
+
+    pub fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(entry) = self.live_entry(key) {
+            Ok(Some(self.read_value(entry)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads several keys at once, returning their values in the same order. The
+    /// records in each segment are read together, which backends such as io_uring
+    /// submit in one go.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = vec![None; keys.len()];
+        // Where each key's record is, grouped by segment, with its place in `keys`.
+        let mut by_segment: BTreeMap<u32, Vec<(usize, KeyDirEntry)>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(entry) = self.live_entry(key) {
+                by_segment.entry(entry.file_id).or_default().push((i, *entry));
+            }
+        }
+        for (file_id, entries) in by_segment {
+            let format = self.cached_segment_header(file_id)?.record_format();
+            let mut unmapped = Vec::new();
+            for (i, entry) in entries {
+                match self.mapped_entry(&entry) {
+                    Some(bytes) => values[i] = Some(decode_value(&entry, bytes, format)?),
+                    None => unmapped.push((i, entry, vec![0; entry.length as usize])),
+                }
+            }
+            if unmapped.is_empty() {
+                continue;
+            }
+            let mut reads: Vec<(u64, &mut [u8])> = unmapped
+                .iter_mut()
+                .map(|(_, entry, buffer)| (entry.offset, buffer.as_mut_slice()))
+                .collect();
+            self.segment(file_id)?.read_many(&mut reads)?;
+            for (i, entry, buffer) in unmapped {
+                values[i] = Some(decode_value(&entry, &buffer, format)?);
+            }
+        }
+        Ok(values)
+    }
+
+    // Reads the value that a keydir entry points at.
+    fn read_value(&self, entry: &KeyDirEntry) -> Result<Vec<u8>> {
+        let format = self.cached_segment_header(entry.file_id)?.record_format();
+        let buffer = self.read_entry(entry)?;
+        decode_value(entry, &buffer, format)
+    }
+
+    /// Rewrites a key that already exists, and does nothing for one that does not.
+    /// Fails with `Error::Expired` if the key is still in the keydir but has expired.
+    pub fn update(
+        &mut self,
+        key: &[u8],
+        updated_value: &[u8],
+        mark_as_deleted: bool,
+        expires_at: Option<u64>,
+    ) -> Result<(), Error> {
+        // Key has to be searched in hashmap
+        if self.live_entry(key).is_some() {
+            return self.write(key, updated_value, mark_as_deleted, expires_at);
+        }
+        if self.index.contains_key(key) {
+            return Err(Error::Expired);
+        }
+        Ok(())
+    }
+
+    pub fn delete_key(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.delete_key_with_options(key, WriteOptions::default())
+    }
+
+    pub fn delete_key_with_options(
+        &mut self,
+        key: &[u8],
+        write_options: WriteOptions,
+    ) -> Result<(), Error> {
+        // First, check if the key exists in the live index.
+        if self.live_entry(key).is_some() {
+            // Append a tombstone record to the log. The value for a tombstone is irrelevant,
+            // so we use an empty slice `&[]`. Our modified `write` function will handle this
+            // without adding the key back to the index.
+            self.write_with_options(key, &[], true, None, write_options)?;
+
+            // Finally, remove the key from the in-memory index to mark it as deleted.
+            self.index.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the keydir from every segment. Returns the torn tail that was cut off
+    /// the active segment, if `RecoveryPolicy::Truncate` had to cut one off.
+    ///
+    /// A record that cannot be read in a sealed segment is always an error: sealed
+    /// segments are never appended to, so it can only be corruption.
+    ///
+    /// Write batches without a commit marker are left out of the keydir. One at the end
+    /// of the active segment is cut off whatever the policy, since none of it was
+    /// acknowledged.
+    pub fn load_db_from_disk(&mut self) -> Result<Option<TornTail>> {
+        self.index.clear(); // Rebuilding from scratch.
+        let mut torn_tail = None;
+
+        // Check every header first so that a segment this build cannot read is reported
+        // before any of it is interpreted, even when its hint file would be used.
+        let sealed_ids: Vec<u32> = self.sealed.keys().copied().collect();
+        for &file_id in sealed_ids.iter() {
+            self.segment_header(file_id)?;
+        }
+        if let Err(e) = self.segment_header(self.active_id) {
+            // The crash happened while the active segment's header was being written, so
+            // the segment holds no records yet and can be started over.
+            if !e.is_io(io::ErrorKind::UnexpectedEof)
+                || self.options.recovery == RecoveryPolicy::Strict
+            {
+                return Err(e);
+            }
+            torn_tail = Some(self.truncate_active(0)?);
+            self.segment_header(self.active_id)?;
+        }
+
+        // Replay the segments oldest first so that the latest record for a key wins.
+        // Sealed segments with a valid hint file are rebuilt without reading any values.
+        for file_id in sealed_ids {
+            if !self.load_hint_file(file_id)? {
+                let header = self.segment_header(file_id)?;
+                let file = &self.sealed[&file_id];
+                let replay = Self::replay_segment(file, file_id, header, &mut self.index)?;
+                if let Some((offset, e)) = replay.failure {
+                    return Err(record_error(
+                        e,
+                        file_id,
+                        offset,
+                        "Record is cut short by the end of a sealed segment",
+                    ));
+                }
+            }
+        }
+        let header = self.segment_header(self.active_id)?;
+        let replay =
+            Self::replay_segment(&self.active, self.active_id, header, &mut self.index)?;
+        self.active_hints = replay.hints;
+        if let Some((offset, e)) = replay.failure {
+            // A torn append leaves a partial record, or zeroes or garbage where the file
+            // grew, and nothing that reads as a record after it. A damaged record, or a
+            // damaged length that makes one run to the end, has good records after it.
+            // Cutting there would drop them.
+            let torn = !record_follows(&self.active, header.record_format(), offset + 1)?;
+            if !torn {
+                return Err(record_error(
+                    e,
+                    self.active_id,
+                    offset,
+                    "Record length runs past the end of the segment, but records follow it",
+                ));
+            }
+            if self.options.recovery == RecoveryPolicy::Strict {
+                return Err(record_error(
+                    e,
+                    self.active_id,
+                    offset,
+                    "Record is cut short by the end of the segment, as by a torn append",
+                ));
+            }
+            // Records of a batch that never committed go with the torn one.
+            let cut = replay.unfinished_batch.unwrap_or(offset);
+            torn_tail = Some(self.truncate_active(cut)?);
+        } else if let Some(offset) = replay.unfinished_batch {
+            // Later appends must not look like part of the batch.
+            torn_tail = Some(self.truncate_active(offset)?);
+        }
+
+        // Appends continue after the last record that was read.
+        self.append_offset = None;
+        self.rebuild_expiry_queue();
+        Ok(torn_tail)
+    }
+
+    /// Cuts the active segment off at `offset`, dropping everything after it.
+    fn truncate_active(&mut self, offset: u64) -> Result<TornTail> {
+        let size = self.active.len()?;
+        self.active.set_len(offset)?;
+        self.append_offset = Some(offset);
+        if offset == 0 {
+            self.headers.remove(&self.active_id);
+        }
+        Ok(TornTail {
+            file_id: self.active_id,
+            offset,
+            dropped_bytes: size - offset,
+        })
+    }
+
+    /// Reads every record in one segment and applies it to `index`, stopping at the
+    /// first record that cannot be read.
+    fn replay_segment(
+        file: &T,
+        file_id: u32,
+        header: SegmentHeader,
+        index: &mut BTreeMap<Vec<u8>, KeyDirEntry>,
+    ) -> Result<SegmentReplay> {
+        // Read from the first record of the segment to the end.
+        let format = header.record_format();
+        let now = unix_now();
+        let mut current_offset = header.data_start();
+        let file_size = file.len()?;
+        let mut reader = CountingReader {
+            inner: BufReader::new(SegmentReader {
+                file,
+                offset: current_offset,
+                end: file_size,
+            }),
+            count: 0,
+        };
+        let mut hints = Vec::new();
+        // Where the open write batch started, and its records so far. They are held back
+        // until its commit marker is read.
+        let mut batch: Option<(u64, Vec<HintEntry>)> = None;
+
+        while current_offset < file_size {
+            let record_start_offset = current_offset;
+
+            // The `parse_record_from_reader` will read exactly one entry from the file.
+            match parse_record_from_reader(&mut reader, format) {
+                Ok(kv) => {
+                    // What the record took up on disk, which is less than its decoded
+                    // size when its value is compressed.
+                    current_offset = header.data_start() + reader.count;
+                    let record_len = current_offset - record_start_offset;
+                    match kv.batch_marker {
+                        Some(BatchMarker::Begin) => {
+                            batch = Some((record_start_offset, Vec::new()));
+                            continue;
+                        }
+                        Some(BatchMarker::Commit) => {
+                            let (_, pending) = batch.take().unwrap_or_default();
+                            for hint in pending {
+                                hint.clone().apply(file_id, index, now);
+                                hints.push(hint);
+                            }
+                            continue;
+                        }
+                        None => {}
+                    }
+                    let hint = HintEntry {
+                        key: kv.key,
+                        offset: record_start_offset,
+                        length: record_len,
+                        write_time: kv.write_time,
+                        expires_at: kv.expires_at,
+                        tombstone: kv.tombstone,
+                        expiry_update: kv.expiry_update,
+                    };
+                    if let Some((_, pending)) = &mut batch {
+                        pending.push(hint);
+                        continue;
+                    }
+                    // The latest record for a key wins, whether it is a value, a tombstone
+                    // or a new expiry for the value before it.
+                    hint.clone().apply(file_id, index, now);
+                    hints.push(hint);
+                }
+                // The segment could not be read, which says nothing about what is in it.
+                Err(Error::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                    return Err(e.into());
+                }
+                Err(e) => {
+                    // A record cut short by the end of the file, or one that fails its
+                    // checksum. Whether that is fatal depends on the segment.
+                    return Ok(SegmentReplay {
+                        hints,
+                        failure: Some((record_start_offset, e)),
+                        unfinished_batch: batch.map(|(start, _)| start),
+                    });
+                }
+            }
+        }
+        Ok(SegmentReplay {
+            hints,
+            failure: None,
+            unfinished_batch: batch.map(|(start, _)| start),
+        })
+    }
+
+    /// Drops every key whose expiry has passed from the keydir and returns how many
+    /// there were. Only keys that are due are looked at, not the whole keydir.
+    pub fn cleanup_expired_keys(&mut self) -> usize {
+        let current_time = unix_now();
+        let mut removed = 0;
+        for (expires_at, key) in self.expiry.pop_due(current_time) {
+            // The key may have been rewritten or deleted since it was scheduled.
+            if self
+                .index
+                .get(&key)
+                .is_some_and(|entry| entry.expires_at == Some(expires_at))
+            {
+                self.index.remove(&key);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn schedule_expiry(&mut self, key: Vec<u8>, expires_at: u64) {
+        self.expiry.schedule(key, expires_at);
+        // Rewritten keys leave stale entries behind. Start over once they make up most
+        // of the queue, so it stays proportional to the keys that can still expire.
+        if self.expiry.len() > 2 * self.index.len() + 1024 {
+            self.rebuild_expiry_queue();
+        }
+    }
+
+    fn rebuild_expiry_queue(&mut self) {
+        self.expiry.clear();
+        for (key, entry) in self.index.iter() {
+            if let Some(expires_at) = entry.expires_at {
+                self.expiry.schedule(key.clone(), expires_at);
+            }
+        }
+    }
+}
+
+/// A record in `segment` at `offset` that could not be read. Replay only stops at an I/O
+/// error when the record is cut short by the end of the segment, which `cut_short` says
+/// what to make of.
+fn record_error(e: Error, segment: u32, offset: u64, cut_short: &str) -> Error {
+    match e {
+        Error::Io(_) => Error::InvalidRecord {
+            segment: Some(segment),
+            offset,
+            reason: cut_short.to_string(),
+        },
+        e => e.at(Some(segment), offset),
+    }
+}
+
+/// Reads a segment front to back through `FileIO::read_at`.
+struct SegmentReader<'a, T> {
+    file: &'a T,
+    offset: u64,
+    end: u64,
+}
+
+impl<T: FileIO> Read for SegmentReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.end - self.offset) as usize;
+        self.file.read_at(&mut buf[..len], self.offset)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+/// Opens a file for reading and appending, creating it if it does not exist.
+pub fn open_file_read_write<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
@@ -0,0 +1,1989 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::{
+        collections::BTreeMap,
+        env,
+        fs,
+        io::{self, SeekFrom},
+        ops::{Add, Bound},
+        path::Path,
+        process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use dance_of_bytes::KeyValue;
+    use crate::{
+        encode_record, parse_record_from_buffer, read_records, read_records_from_file, BatchMarker, Codec, Compression, Db,
+        Error, ReadPath, Record, RecordFormat, SegmentHeader, WriteBatch, FORMAT_VERSION,
+        SEGMENT_HEADER_LEN, SEGMENT_MAGIC,
+    };
+
+    use crate::durability::{Flusher, SyncPolicy, WriteOptions};
+    use crate::expiry::{ExpiryQueue, ExpiryWorker, Ttl};
+    use crate::fault::{FaultInjector, FaultyDir};
+    use crate::handle::StorageHandle;
+    use crate::memory::{MemFile, MemSegmentDir};
+    use crate::segment::{FsSegmentDir, SegmentDir};
+    use crate::{
+        FileIO, RecoveryPolicy, SStStorage, StorageOptions, TornTail,
+    };
+
+    /// A directory under the system temp dir, named after the test, that is deleted when
+    /// it goes out of scope, even if the test fails.
+    struct TempDir(String);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("bitcask_test_{}_{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            TempDir(path.to_string_lossy().into_owned())
+        }
+
+        fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A data directory on disk, created if it does not exist.
+    fn fs_dir(path: &str) -> Arc<FsSegmentDir> {
+        Arc::new(FsSegmentDir::new(path).expect("Failed to create temp dir"))
+    }
+
+    /// Opens the storage in `dir` without loading its keydir.
+    fn open_storage<T: FileIO + Send + 'static>(
+        dir: Arc<dyn SegmentDir<T>>,
+        options: StorageOptions,
+    ) -> SStStorage<T> {
+        SStStorage::open(dir, options).unwrap()
+    }
+
+    /// Opens the storage in `dir` and loads its keydir.
+    fn load_storage<T: FileIO + Send + 'static>(
+        dir: Arc<dyn SegmentDir<T>>,
+        options: StorageOptions,
+    ) -> SStStorage<T> {
+        let mut sst_storage = open_storage(dir, options);
+        sst_storage.load_db_from_disk().unwrap();
+        sst_storage
+    }
+
+    #[test]
+    fn test_write() {
+        let file = MemFile::new();
+        let contents = file.try_clone().unwrap();
+        let mut sst_storage = SStStorage::new(file);
+
+        let key = vec![1, 2, 3];
+        let value = vec![4, 5, 6];
+        let timestamp = Some(10);
+        let (lower_bound, upper_bound) = generate_timestamp_range(10);
+
+        // Call the write method and validate the result
+        let result = sst_storage.write(&key, &value, false, timestamp);
+        assert!(result.is_ok());
+
+        let records = read_records(&contents.contents().unwrap()).unwrap();
+
+        // Validate that the key and value were written correctly
+        assert_eq!(records[0].key, &key[..]);
+        assert_eq!(records[0].value, &value[..]);
+        // The record is stamped with when it was written, and keeps the expiry it was given.
+        let read_timestamp = records[0].write_time.unwrap();
+        assert!(
+            read_timestamp >= lower_bound && read_timestamp <= upper_bound,
+            "Timestamp {} is not within expected range {} to {}",
+            read_timestamp,
+            lower_bound,
+            upper_bound
+        );
+        assert_eq!(records[0].expires_at, timestamp);
+    }
+
+    #[test]
+    fn test_insert_key_and_read_existing_key() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        let key = b"my_key".to_vec();
+        let value = b"my_value".to_vec();
+        
+        // Writing a known kv pair to the file
+        sst_storage.write(&key, &value, false, None).unwrap();
+        
+        // Reading the kv pair from the file
+        let read_value = sst_storage.read(&key).unwrap();
+        assert_eq!(read_value, Some(value));
+    }
+
+    #[test]
+    fn test_insert_key_and_read_non_existing_key() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        let key = b"my_key".to_vec();
+        let value = b"my_value".to_vec();
+
+        // Writing a known kv pair to the file
+        sst_storage.write(&key, &value, false, None).unwrap();
+
+        // Reading the kv pair from the file that does not exist
+        let non_existent_key = b"non_existent_key".to_vec();
+        let read_value = sst_storage.read(&non_existent_key ).unwrap();
+        assert_eq!(read_value, None);
+    }
+
+    #[test]
+    fn test_update_existing_key() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        // Insert a known kv pair to the file
+        let key = b"my_key".to_vec();
+        let value = b"my_value".to_vec();
+        let timestamp = None;
+        sst_storage.write(&key, &value, false, timestamp).unwrap();
+        
+        // Update the kv pair
+        let updated_value = b"updated_value".to_vec();
+        let updated_timestamp = None;
+        sst_storage.write(&key, &updated_value, false, updated_timestamp).unwrap();
+
+        // Reading the kv pair from the file
+        let read_value = sst_storage.read(&key).unwrap();
+        assert_eq!(read_value, Some(updated_value));
+    }
+
+    #[test]
+    fn test_delete_existing_key() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        // Insert a known kv pair to the file
+        let key = b"my_key".to_vec();
+        let value = b"my_value".to_vec();
+        let timestamp = None;
+        sst_storage.write(&key, &value, false, timestamp).unwrap();
+
+        // Delete the kv pair
+        sst_storage.delete_key(&key).unwrap();
+
+        // Reading the kv pair from the file
+        let read_value = sst_storage.read(&key).unwrap();
+        assert_eq!(read_value, None);
+    }
+
+    #[test]
+    fn test_rollover_seals_active_segment() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 80,
+            ..StorageOptions::default()
+        };
+        let mut sst_storage = SStStorage::open(dir.clone(), options).unwrap();
+
+        // Every record is 27 bytes, so after the 18 byte header an 80 byte segment holds
+        // two of them.
+        for i in 0..6u8 {
+            sst_storage.write(&[b'k', i], b"some_value", false, None).unwrap();
+        }
+
+        assert_eq!(dir.segment_ids().unwrap(), vec![1, 2, 3]);
+        assert_eq!(sst_storage.active_id, 3);
+        assert_eq!(sst_storage.index.get(&vec![b'k', 0]).unwrap().file_id, 1);
+        assert_eq!(sst_storage.index.get(&vec![b'k', 5]).unwrap().file_id, 3);
+        for i in 0..6u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(b"some_value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_load_db_from_disk_replays_all_segments() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 64,
+            ..StorageOptions::default()
+        };
+
+        {
+            let mut sst_storage = open_storage(dir.clone(), options);
+            sst_storage.write(b"first", b"old_value", false, None).unwrap();
+            sst_storage.write(b"second", b"value", false, None).unwrap();
+            sst_storage.write(b"third", b"value", false, None).unwrap();
+            // The newer record for `first` and the tombstone for `second` land in a later segment.
+            sst_storage.write(b"first", b"new_value", false, None).unwrap();
+            sst_storage.delete_key(b"second").unwrap();
+        }
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        sst_storage.load_db_from_disk().unwrap();
+        assert!(!sst_storage.sealed.is_empty());
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"new_value".to_vec()));
+        assert_eq!(sst_storage.read(b"second").unwrap(), None);
+        assert_eq!(sst_storage.read(b"third").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_merge_keeps_only_live_records() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 64,
+            ..StorageOptions::default()
+        };
+        let (_, one_hour_from_now) = generate_timestamp_range(60);
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        for round in 0..5u8 {
+            let value = [b'v', round];
+            sst_storage.write(b"overwritten", &value, false, None).unwrap();
+            sst_storage.write(b"deleted", &value, false, None).unwrap();
+        }
+        sst_storage.delete_key(b"deleted").unwrap();
+        sst_storage.write(b"expired", b"value", false, Some(1)).unwrap();
+        sst_storage.write(b"unexpired", b"value", false, Some(one_hour_from_now)).unwrap();
+
+        let handle = StorageHandle::new(sst_storage);
+        let stats = handle.merge().unwrap();
+        assert_eq!(stats.records_kept, 2);
+        assert!(stats.bytes_reclaimed > 0);
+        assert_eq!(handle.read(b"overwritten").unwrap(), Some(vec![b'v', 4]));
+        assert_eq!(handle.read(b"deleted").unwrap(), None);
+        assert_eq!(handle.read(b"expired").unwrap(), None);
+        assert_eq!(handle.read(b"unexpired").unwrap(), Some(b"value".to_vec()));
+
+        // Writes after the merge go to a segment that replays after the merged ones.
+        handle.write(b"overwritten", b"after_merge", false, None).unwrap();
+        drop(handle);
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(sst_storage.index.len(), 2);
+        assert_eq!(sst_storage.read(b"overwritten").unwrap(), Some(b"after_merge".to_vec()));
+        assert_eq!(sst_storage.read(b"deleted").unwrap(), None);
+        assert_eq!(sst_storage.read(b"unexpired").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_merge_of_records_that_grow_keeps_later_writes() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 256,
+            ..StorageOptions::default()
+        };
+
+        // A legacy segment of v1 records, which all get longer when converted to v2.
+        let mut legacy = Vec::new();
+        for i in 0..100u8 {
+            legacy.extend(KeyValue::new(&[b'k', i], &[i; 8], Some(0), false, 0).to_buffer());
+        }
+        dir.open_segment(1).unwrap().write(&legacy).unwrap();
+
+        let db = Db::open_dir(dir.clone(), options).unwrap();
+        let stats = db.merge().unwrap();
+        assert_eq!(stats.records_kept, 100);
+        db.put(b"after", b"merge").unwrap();
+        db.close().unwrap();
+
+        let db = Db::open_dir(dir.clone(), options).unwrap();
+        assert_eq!(db.get(b"after").unwrap(), Some(b"merge".to_vec()));
+        for i in 0..100u8 {
+            assert_eq!(db.get(&[b'k', i]).unwrap(), Some(vec![i; 8]));
+        }
+    }
+
+    #[test]
+    fn test_load_db_from_disk_uses_hint_files() {
+        let temp_dir = TempDir::new("hints");
+        let temp_dir_path = temp_dir.path();
+        let options = StorageOptions {
+            max_segment_size: 64,
+            ..StorageOptions::default()
+        };
+
+        {
+            let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+            for i in 0..6u8 {
+                sst_storage.write(&[b'k', i], b"some_value", false, None).unwrap();
+            }
+            sst_storage.delete_key(&[b'k', 0]).unwrap();
+        }
+        let hint_path = format!("{}/000001.hint", temp_dir_path);
+        assert!(fs::metadata(&hint_path).is_ok());
+
+        // Damage a value in the first segment. Its hint file means the value is never read
+        // while the keydir is rebuilt.
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let mut segment = fs::read(&segment_path).unwrap();
+        segment[25] ^= 0xFF;
+        fs::write(&segment_path, segment).unwrap();
+
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+        sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(sst_storage.index.len(), 5);
+        assert!(!sst_storage.index.contains_key(&vec![b'k', 0]));
+        assert_eq!(sst_storage.read(&[b'k', 5]).unwrap(), Some(b"some_value".to_vec()));
+        drop(sst_storage);
+
+        // A damaged hint file is ignored and the segment is scanned instead.
+        let mut hint = fs::read(&hint_path).unwrap();
+        let last = hint.len() - 1;
+        hint[last] ^= 0xFF;
+        fs::write(&hint_path, hint).unwrap();
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+        assert!(sst_storage.load_db_from_disk().is_err());
+    }
+
+    #[test]
+    fn test_large_key_and_value_roundtrip() {
+        let dir = Arc::new(MemSegmentDir::new());
+
+        let key = vec![b'k'; 300];
+        let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        {
+            let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+            sst_storage.write(&key, &value, false, None).unwrap();
+            assert_eq!(sst_storage.read(&key).unwrap(), Some(value.clone()));
+        }
+
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(sst_storage.read(&key).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_legacy_v1_segment_is_still_readable() {
+        let temp_dir = TempDir::new("legacy_segment");
+        let temp_dir_path = temp_dir.path();
+        fs::create_dir_all(temp_dir_path).unwrap();
+
+        // A segment written before the v2 format existed: u8 lengths and no preamble.
+        let legacy = KeyValue::new(b"legacy_key", b"legacy_value", Some(0), false, 0);
+        fs::write(format!("{}/000001.data", temp_dir_path), legacy.to_buffer()).unwrap();
+
+        let mut sst_storage = load_storage(fs_dir(temp_dir_path), StorageOptions::default());
+        assert_eq!(sst_storage.read(b"legacy_key").unwrap(), Some(b"legacy_value".to_vec()));
+
+        // New records do not fit the legacy format, so they go into a new v2 segment.
+        let long_key = vec![b'k'; 256];
+        sst_storage.write(&long_key, b"value", false, None).unwrap();
+        assert_eq!(sst_storage.index.get(&long_key).unwrap().file_id, 2);
+        assert_eq!(sst_storage.read(&long_key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(sst_storage.read(b"legacy_key").unwrap(), Some(b"legacy_value".to_vec()));
+    }
+
+    #[test]
+    fn test_load_db_from_disk_rejects_unknown_format_version() {
+        let temp_dir = TempDir::new("unknown_version");
+        let temp_dir_path = temp_dir.path();
+
+        {
+            let mut sst_storage = open_storage(fs_dir(temp_dir_path), StorageOptions::default());
+            sst_storage.write(b"my_key", b"my_value", false, None).unwrap();
+        }
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let mut segment = fs::read(&segment_path).unwrap();
+        assert_eq!(&segment[..4], SEGMENT_MAGIC);
+        assert_eq!(segment[4], FORMAT_VERSION);
+
+        // Pretend the segment was written by a future version, header checksum and all.
+        let mut header = SegmentHeader::decode(&segment).unwrap();
+        header.version = FORMAT_VERSION + 1;
+        segment[..SEGMENT_HEADER_LEN].copy_from_slice(&header.encode());
+        fs::write(&segment_path, segment).unwrap();
+
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), StorageOptions::default());
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::UnsupportedVersion { segment: Some(1), version } if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_torn_tail_is_truncated_or_refused() {
+        let temp_dir = TempDir::new("torn_tail");
+        let temp_dir_path = temp_dir.path();
+        let options = |recovery| StorageOptions {
+            recovery,
+            ..StorageOptions::default()
+        };
+
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let first_record_end;
+        {
+            let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Strict));
+            sst_storage.write(b"first", b"value", false, None).unwrap();
+            first_record_end = fs::metadata(&segment_path).unwrap().len();
+            sst_storage.write(b"second", b"value", false, None).unwrap();
+        }
+        // Simulate a crash halfway through appending the second record.
+        let mut segment = fs::read(&segment_path).unwrap();
+        segment.truncate(segment.len() - 6);
+        let torn_size = segment.len() as u64;
+        fs::write(&segment_path, segment).unwrap();
+
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Strict));
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidRecord { segment: Some(1), offset, .. } if offset == first_record_end
+        ));
+        drop(sst_storage);
+
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Truncate));
+        let torn_tail = sst_storage.load_db_from_disk().unwrap().unwrap();
+        assert_eq!(
+            torn_tail,
+            TornTail {
+                file_id: 1,
+                offset: first_record_end,
+                dropped_bytes: torn_size - first_record_end,
+            }
+        );
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), first_record_end);
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(sst_storage.read(b"second").unwrap(), None);
+
+        // Appends continue from the cut and reload cleanly.
+        sst_storage.write(b"third", b"value", false, None).unwrap();
+        drop(sst_storage);
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Strict));
+        assert_eq!(sst_storage.load_db_from_disk().unwrap(), None);
+        assert_eq!(sst_storage.read(b"third").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_zero_filled_tail_is_truncated() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = |recovery| StorageOptions {
+            recovery,
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = open_storage(dir.clone(), options(RecoveryPolicy::Strict));
+        sst_storage.write(b"first", b"value", false, None).unwrap();
+        let first_record_end = sst_storage.active.len().unwrap();
+        drop(sst_storage);
+        // A crash after the file grew but before the record reached it leaves zeroes.
+        let mut segment = dir.open_segment(1).unwrap();
+        segment.write_at(&[0; 4096], first_record_end).unwrap();
+
+        let mut sst_storage = open_storage(dir.clone(), options(RecoveryPolicy::Strict));
+        assert!(sst_storage.load_db_from_disk().is_err());
+        drop(sst_storage);
+
+        let mut sst_storage = open_storage(dir.clone(), options(RecoveryPolicy::Truncate));
+        let torn_tail = sst_storage.load_db_from_disk().unwrap().unwrap();
+        assert_eq!(
+            torn_tail,
+            TornTail {
+                file_id: 1,
+                offset: first_record_end,
+                dropped_bytes: 4096,
+            }
+        );
+        assert_eq!(segment.len().unwrap(), first_record_end);
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_large_torn_record_is_truncated() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            recovery: RecoveryPolicy::Truncate,
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        sst_storage.write(b"first", b"value", false, None).unwrap();
+        let first_record_end = sst_storage.active.len().unwrap();
+        // Random bytes hold plenty of starts that look like a record header.
+        let mut rng = StdRng::seed_from_u64(7);
+        let value: Vec<u8> = (0..1 << 20).map(|_| rng.gen()).collect();
+        sst_storage.write(b"large", &value, false, None).unwrap();
+        let size = sst_storage.active.len().unwrap();
+        drop(sst_storage);
+        dir.open_segment(1).unwrap().set_len(size - 100).unwrap();
+
+        // Every start in the torn record is ruled out without scanning the rest of it.
+        let started = std::time::Instant::now();
+        let mut sst_storage = open_storage(dir.clone(), options);
+        let torn_tail = sst_storage.load_db_from_disk().unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+        assert_eq!(torn_tail.offset, first_record_end);
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(sst_storage.read(b"large").unwrap(), None);
+    }
+
+    #[test]
+    fn test_corrupt_record_before_the_tail_is_not_truncated() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            recovery: RecoveryPolicy::Truncate,
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        for i in 0..4u8 {
+            sst_storage.write(&[b'k', i], b"some_value", false, None).unwrap();
+        }
+        let size = sst_storage.active.len().unwrap();
+        drop(sst_storage);
+        // Flip a byte in the value of the first record, which the three after it follow.
+        let mut segment = dir.open_segment(1).unwrap();
+        let mut byte = [0];
+        segment.read_at(&mut byte, SEGMENT_HEADER_LEN as u64 + 15).unwrap();
+        segment.write_at(&[byte[0] ^ 0xFF], SEGMENT_HEADER_LEN as u64 + 15).unwrap();
+
+        let mut sst_storage = open_storage(dir.clone(), options);
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Corruption { segment: Some(1), offset, .. } if offset == SEGMENT_HEADER_LEN as u64
+        ));
+        assert_eq!(segment.len().unwrap(), size);
+        drop(sst_storage);
+        segment.write_at(&byte, SEGMENT_HEADER_LEN as u64 + 15).unwrap();
+
+        // Set the high bit of the first record's value length instead. The record now
+        // claims to run to the end of the segment, like one cut short by a crash.
+        let value_len_offset = SEGMENT_HEADER_LEN as u64 + 2;
+        segment.read_at(&mut byte, value_len_offset).unwrap();
+        segment.write_at(&[byte[0] | 0x80], value_len_offset).unwrap();
+        let mut sst_storage = open_storage(dir.clone(), options);
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidRecord { segment: Some(1), offset, .. } if offset == SEGMENT_HEADER_LEN as u64
+        ));
+        assert_eq!(segment.len().unwrap(), size);
+        drop(sst_storage);
+
+        segment.write_at(&byte, value_len_offset).unwrap();
+        let sst_storage = load_storage(dir.clone(), options);
+        for i in 0..4u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(b"some_value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_sealed_segment_is_not_truncated() {
+        let temp_dir = TempDir::new("corrupt_sealed");
+        let temp_dir_path = temp_dir.path();
+        let options = StorageOptions {
+            max_segment_size: 64,
+            recovery: RecoveryPolicy::Truncate,
+            ..StorageOptions::default()
+        };
+
+        {
+            let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+            for i in 0..6u8 {
+                sst_storage.write(&[b'k', i], b"some_value", false, None).unwrap();
+            }
+        }
+        // Damage the first record of the first segment and remove its hint file so the
+        // segment has to be scanned.
+        fs::remove_file(format!("{}/000001.hint", temp_dir_path)).unwrap();
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let mut segment = fs::read(&segment_path).unwrap();
+        let size = segment.len();
+        segment[25] ^= 0xFF;
+        fs::write(&segment_path, &segment).unwrap();
+
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Corruption { segment: Some(1), offset: 18, expected, actual } if expected != actual
+        ));
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), size as u64);
+        drop(sst_storage);
+
+        // A sealed segment is never appended to, so a record cut short in one is not torn.
+        segment[25] ^= 0xFF;
+        fs::write(&segment_path, &segment[..size - 2]).unwrap();
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+        let error = sst_storage.load_db_from_disk().unwrap_err();
+        assert!(matches!(error, Error::InvalidRecord { segment: Some(1), offset: 18, .. }));
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), size as u64 - 2);
+    }
+
+    #[test]
+    fn test_invalid_records_are_reported_where_they_start() {
+        // Lays out a v2 record with whatever flags and lengths it is given, and a
+        // checksum that matches them.
+        let raw_record = |flags: u8, lengths: &[u8], key_and_value: &[u8]| {
+            let mut buffer = vec![flags];
+            buffer.extend_from_slice(lengths);
+            buffer.extend_from_slice(key_and_value);
+            let checksum = crc32fast::hash(&buffer);
+            buffer.extend_from_slice(&checksum.to_le_bytes());
+            buffer
+        };
+        let both_codecs = raw_record(0xC0, &[1, 0], b"k");
+        let oversized_length = raw_record(0, &[0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0], b"");
+        let not_lz4 = raw_record(1 << 6, &[1, 3], b"k\xFF\xFF\xFF");
+        for raw in [&both_codecs, &oversized_length, &not_lz4] {
+            let error = parse_record_from_buffer(raw, RecordFormat::V2).unwrap_err();
+            assert!(matches!(error, Error::InvalidRecord { segment: None, offset: 0, .. }), "{}", error);
+        }
+
+        // Within a segment, the offset is where the record starts.
+        let good = encode_record(&Record::new(b"k", b"v", false), RecordFormat::V2).unwrap();
+        let mut contents = SegmentHeader::new(0).encode();
+        contents.extend_from_slice(&good);
+        contents.extend_from_slice(&not_lz4);
+        let error = read_records(&contents).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidRecord { segment: None, offset, .. }
+                if offset == (SEGMENT_HEADER_LEN + good.len()) as u64
+        ));
+        assert!(error.to_string().contains("does not decompress"), "{}", error);
+    }
+
+    const SECONDS_IN_MINS: u64 = 60;
+
+    fn generate_timestamp_range(minutes: u64) -> (u64, u64) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let lower_bound = now.as_secs();
+        let upper_bound = now
+            .add(Duration::from_secs(minutes * SECONDS_IN_MINS))
+            .as_secs();
+        (lower_bound, upper_bound)
+    }
+
+    /// A file that counts how often it is synced.
+    struct CountingFile {
+        file: MemFile,
+        syncs: Arc<AtomicUsize>,
+    }
+
+    impl FileIO for CountingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.file.write(buf)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.file.read_at(buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.file.write_at(buf, offset)
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            FileIO::len(&self.file)
+        }
+
+        fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek_from(pos)
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            FileIO::set_len(&mut self.file, len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            FileIO::sync(&mut self.file)
+        }
+
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(CountingFile {
+                file: FileIO::try_clone(&self.file)?,
+                syncs: self.syncs.clone(),
+            })
+        }
+    }
+
+    fn counting_storage(sync: SyncPolicy) -> (SStStorage<CountingFile>, Arc<AtomicUsize>) {
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let file = CountingFile { file: MemFile::new(), syncs: syncs.clone() };
+        let mut sst_storage = SStStorage::new(file);
+        sst_storage.options.sync = sync;
+        (sst_storage, syncs)
+    }
+
+    #[test]
+    fn test_sync_policy_decides_when_writes_are_synced() {
+        let (mut sst_storage, syncs) = counting_storage(SyncPolicy::Always);
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 2);
+
+        let (mut sst_storage, syncs) = counting_storage(SyncPolicy::Never);
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+
+        let (mut sst_storage, syncs) = counting_storage(SyncPolicy::EveryBytes(u64::MAX));
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        let record_len = sst_storage.active.len().unwrap() - SEGMENT_HEADER_LEN as u64;
+        sst_storage.options.sync = SyncPolicy::EveryBytes(2 * record_len);
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_write_options_override_sync_policy() {
+        let (mut sst_storage, syncs) = counting_storage(SyncPolicy::Never);
+        sst_storage.write_with_options(b"key", b"value", false, None, WriteOptions { sync: Some(true) }).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        let (mut sst_storage, syncs) = counting_storage(SyncPolicy::Always);
+        sst_storage.write_with_options(b"key", b"value", false, None, WriteOptions { sync: Some(false) }).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+        assert_eq!(sst_storage.read(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_db_write_options_survive_a_crash() {
+        let mem_dir = Arc::new(MemSegmentDir::new());
+        let open = |injector: &Arc<FaultInjector<MemFile>>| {
+            let dir = FaultyDir::new(mem_dir.clone(), injector.clone());
+            let options = StorageOptions {
+                sync: SyncPolicy::Never,
+                ..StorageOptions::default()
+            };
+            Db::open_dir(Arc::new(dir), options).unwrap()
+        };
+        let synced = WriteOptions { sync: Some(true) };
+
+        let injector = Arc::new(FaultInjector::new());
+        let db = open(&injector);
+        db.put_with_options(b"synced", b"value", synced).unwrap();
+        db.put(b"unsynced", b"value").unwrap();
+        injector.crash().unwrap();
+        drop(db);
+
+        let injector = Arc::new(FaultInjector::new());
+        let db = open(&injector);
+        assert_eq!(db.get(b"synced").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"unsynced").unwrap(), None);
+        db.delete_with_options(b"synced", synced).unwrap();
+        injector.crash().unwrap();
+        drop(db);
+
+        let injector = Arc::new(FaultInjector::new());
+        let db = open(&injector);
+        assert_eq!(db.get(b"synced").unwrap(), None);
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").put(b"b", b"2");
+        db.write_batch_with_options(&batch, synced).unwrap();
+        db.put(b"unsynced", b"value").unwrap();
+        injector.crash().unwrap();
+        drop(db);
+
+        let db = open(&Arc::new(FaultInjector::new()));
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"unsynced").unwrap(), None);
+    }
+
+    #[test]
+    fn test_flusher_syncs_in_the_background_and_on_drop() {
+        let (sst_storage, syncs) = counting_storage(SyncPolicy::Never);
+
+        let flusher = Flusher::start(sst_storage.active.try_clone().unwrap(), Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(100));
+        assert!(syncs.load(Ordering::SeqCst) > 0);
+
+        drop(flusher);
+        let after_drop = syncs.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(syncs.load(Ordering::SeqCst), after_drop);
+    }
+
+    #[test]
+    fn test_sync_policy_parses_from_str() {
+        assert_eq!("always".parse::<SyncPolicy>(), Ok(SyncPolicy::Always));
+        assert_eq!("never".parse::<SyncPolicy>(), Ok(SyncPolicy::Never));
+        assert_eq!("every-ms:250".parse::<SyncPolicy>(), Ok(SyncPolicy::EveryMillis(250)));
+        assert_eq!("every-bytes:4096".parse::<SyncPolicy>(), Ok(SyncPolicy::EveryBytes(4096)));
+        assert!("every-ms:soon".parse::<SyncPolicy>().is_err());
+        assert!("every-ms:0".parse::<SyncPolicy>().is_err());
+        let options = StorageOptions {
+            sync: SyncPolicy::EveryMillis(0),
+            ..StorageOptions::default()
+        };
+        let error = Db::in_memory(options).err().unwrap();
+        assert!(error.is_io(io::ErrorKind::InvalidInput));
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_recovery_policy_parses_from_str() {
+        assert_eq!("strict".parse::<RecoveryPolicy>(), Ok(RecoveryPolicy::Strict));
+        assert_eq!("truncate".parse::<RecoveryPolicy>(), Ok(RecoveryPolicy::Truncate));
+        assert!("lenient".parse::<RecoveryPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_reads_share_storage_and_leave_appends_alone() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        // Reading between appends must not change where the next record goes.
+        sst_storage.write(b"first", b"one", false, None).unwrap();
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"one".to_vec()));
+        sst_storage.write(b"second", b"two", false, None).unwrap();
+        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"one".to_vec()));
+        sst_storage.write(b"third", b"three", false, None).unwrap();
+
+        let sst_storage = &sst_storage;
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(sst_storage.read(b"first").unwrap(), Some(b"one".to_vec()));
+                        assert_eq!(sst_storage.read(b"second").unwrap(), Some(b"two".to_vec()));
+                        assert_eq!(sst_storage.read(b"third").unwrap(), Some(b"three".to_vec()));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_handle_serves_readers_while_writing_and_merging() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 256,
+            ..StorageOptions::default()
+        };
+        let mut sst_storage = SStStorage::open(dir.clone(), options).unwrap();
+        for i in 0..20u8 {
+            sst_storage.write(&[b'k', i], &[b'v', i, 0], false, None).unwrap();
+        }
+        let handle = StorageHandle::new(sst_storage);
+
+        thread::scope(|scope| {
+            // The writer overwrites every key, round by round, and merges in between.
+            let writer = handle.clone();
+            scope.spawn(move || {
+                for round in 1..=5u8 {
+                    for i in 0..20u8 {
+                        writer.write(&[b'k', i], &[b'v', i, round], false, None).unwrap();
+                    }
+                    writer.merge().unwrap();
+                }
+            });
+            for _ in 0..4 {
+                let reader = handle.clone();
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        for i in 0..20u8 {
+                            let value = reader.read(&[b'k', i]).unwrap().expect("key is never deleted");
+                            assert_eq!(&value[..2], &[b'v', i]);
+                        }
+                    }
+                });
+            }
+        });
+
+        for i in 0..20u8 {
+            assert_eq!(handle.read(&[b'k', i]).unwrap(), Some(vec![b'v', i, 5]));
+        }
+        drop(handle);
+
+        // Everything the last merge left behind replays to the same values.
+        let sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        for i in 0..20u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(vec![b'v', i, 5]));
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_writes_made_while_copying() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.write(b"overwritten", b"old", false, None).unwrap();
+        sst_storage.write(b"deleted", b"old", false, None).unwrap();
+        sst_storage.write(b"untouched", b"old", false, None).unwrap();
+
+        // Run the copy by hand so that writes can land between the phases.
+        let plan = sst_storage.plan_merge().unwrap().unwrap();
+        let output = plan.copy().unwrap();
+        sst_storage.write(b"overwritten", b"new", false, None).unwrap();
+        sst_storage.delete_key(b"deleted").unwrap();
+        sst_storage.install_merge(output).unwrap();
+
+        assert_eq!(sst_storage.read(b"overwritten").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(sst_storage.read(b"deleted").unwrap(), None);
+        assert_eq!(sst_storage.read(b"untouched").unwrap(), Some(b"old".to_vec()));
+        drop(sst_storage);
+
+        let sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        assert_eq!(sst_storage.read(b"overwritten").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(sst_storage.read(b"deleted").unwrap(), None);
+        assert_eq!(sst_storage.read(b"untouched").unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn test_write_time_and_expiry_survive_reload() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let (lower_bound, one_hour_from_now) = generate_timestamp_range(60);
+
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.write(b"expiring", b"value", false, Some(one_hour_from_now)).unwrap();
+        sst_storage.write(b"forever", b"value", false, None).unwrap();
+        // Seal the segment so that one load goes through its hint file.
+        sst_storage.rollover().unwrap();
+        sst_storage.write(b"active", b"value", false, Some(one_hour_from_now)).unwrap();
+        drop(sst_storage);
+
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.load_db_from_disk().unwrap();
+        for key in [&b"expiring"[..], b"active"] {
+            let entry = sst_storage.index.get(key).unwrap();
+            assert!(entry.write_time.unwrap() >= lower_bound);
+            assert_eq!(entry.expires_at, Some(one_hour_from_now));
+        }
+        let entry = sst_storage.index.get(&b"forever"[..]).unwrap();
+        assert!(entry.write_time.unwrap() >= lower_bound);
+        assert_eq!(entry.expires_at, None);
+    }
+
+    #[test]
+    fn test_v1_records_cannot_hold_an_expiry() {
+        let record = Record { expires_at: Some(1), ..Record::new(b"key", b"value", false) };
+        assert!(encode_record(&record, RecordFormat::V1).is_err());
+        assert!(encode_record(&record, RecordFormat::V2).is_ok());
+    }
+
+    #[test]
+    fn test_expired_keys_stay_expired_after_restart() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let (_, one_hour_from_now) = generate_timestamp_range(60);
+
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        // In the segment that gets sealed, and so is loaded from its hint file.
+        sst_storage.write(b"sealed", b"old_value", false, None).unwrap();
+        sst_storage.write(b"sealed", b"expired_value", false, Some(1)).unwrap();
+        sst_storage.rollover().unwrap();
+        // In the active segment, which is always scanned.
+        sst_storage.write(b"active", b"old_value", false, None).unwrap();
+        sst_storage.write(b"active", b"expired_value", false, Some(1)).unwrap();
+        sst_storage.write(b"unexpired", b"value", false, Some(one_hour_from_now)).unwrap();
+
+        // Expired keys are unreadable even before cleanup drops them from the keydir.
+        assert_eq!(sst_storage.read(b"sealed").unwrap(), None);
+        assert_eq!(sst_storage.read(b"active").unwrap(), None);
+        assert_eq!(sst_storage.read(b"unexpired").unwrap(), Some(b"value".to_vec()));
+        drop(sst_storage);
+
+        // Neither the expired value nor the one it replaced comes back.
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.load_db_from_disk().unwrap();
+        assert!(!sst_storage.index.contains_key(&b"sealed"[..]));
+        assert!(!sst_storage.index.contains_key(&b"active"[..]));
+        assert_eq!(sst_storage.read(b"sealed").unwrap(), None);
+        assert_eq!(sst_storage.read(b"active").unwrap(), None);
+        assert_eq!(sst_storage.read(b"unexpired").unwrap(), Some(b"value".to_vec()));
+
+        // Nor after a merge has rewritten everything.
+        let handle = StorageHandle::new(sst_storage);
+        assert_eq!(handle.merge().unwrap().records_kept, 1);
+        drop(handle);
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+        sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(sst_storage.index.len(), 1);
+        assert_eq!(sst_storage.read(b"unexpired").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_expiry_queue_pops_due_keys_soonest_first() {
+        let mut queue = ExpiryQueue::default();
+        queue.schedule(b"late".to_vec(), 30);
+        queue.schedule(b"early".to_vec(), 10);
+        queue.schedule(b"middle".to_vec(), 20);
+        assert_eq!(queue.next_deadline(), Some(10));
+
+        assert_eq!(queue.pop_due(5), vec![]);
+        assert_eq!(queue.pop_due(20), vec![(10, b"early".to_vec()), (20, b"middle".to_vec())]);
+        assert_eq!(queue.next_deadline(), Some(30));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_cleanup_expired_keys_only_drops_due_keys() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+        let (_, one_hour_from_now) = generate_timestamp_range(60);
+
+        sst_storage.write(b"expired", b"value", false, Some(1)).unwrap();
+        sst_storage.write(b"unexpired", b"value", false, Some(one_hour_from_now)).unwrap();
+        sst_storage.write(b"forever", b"value", false, None).unwrap();
+        // Rewritten without an expiry after it was scheduled to expire.
+        sst_storage.write(b"rewritten", b"value", false, Some(1)).unwrap();
+        sst_storage.write(b"rewritten", b"new_value", false, None).unwrap();
+
+        assert_eq!(sst_storage.cleanup_expired_keys(), 1);
+        assert!(!sst_storage.index.contains_key(&b"expired"[..]));
+        assert_eq!(sst_storage.index.len(), 3);
+        assert_eq!(sst_storage.read(b"rewritten").unwrap(), Some(b"new_value".to_vec()));
+        // Only the unexpired key is left to wait for.
+        assert_eq!(sst_storage.expiry.next_deadline(), Some(one_hour_from_now));
+        assert_eq!(sst_storage.cleanup_expired_keys(), 0);
+    }
+
+    #[test]
+    fn test_expiry_worker_drops_keys_in_the_background() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        let handle = StorageHandle::new(sst_storage);
+        let worker = ExpiryWorker::start(handle.clone(), Duration::from_millis(10));
+
+        handle.write(b"expired", b"value", false, Some(1)).unwrap();
+        handle.write(b"forever", b"value", false, None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(worker);
+
+        assert_eq!(handle.next_expiry().unwrap(), None);
+        assert_eq!(handle.cleanup_expired_keys().unwrap(), 0);
+        assert_eq!(handle.read(b"forever").unwrap(), Some(b"value".to_vec()));
+        drop(handle);
+    }
+
+    #[test]
+    fn test_ttl_operations() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let mut sst_storage = open_storage(dir.clone(), StorageOptions::default());
+
+        sst_storage.put_with_ttl(b"key", b"value", Duration::from_secs(60)).unwrap();
+        match sst_storage.ttl(b"key") {
+            Ttl::Expires(ttl) => assert!(ttl > Duration::from_secs(58) && ttl <= Duration::from_secs(60)),
+            other => panic!("Unexpected TTL {:?}", other),
+        }
+
+        assert!(sst_storage.persist(b"key").unwrap());
+        assert_eq!(sst_storage.ttl(b"key"), Ttl::Persistent);
+        // Nothing left to remove.
+        assert!(!sst_storage.persist(b"key").unwrap());
+
+        assert!(sst_storage.expire(b"key", Duration::from_secs(3600)).unwrap());
+        assert!(matches!(sst_storage.ttl(b"key"), Ttl::Expires(ttl) if ttl > Duration::from_secs(3500)));
+        assert_eq!(sst_storage.read(b"key").unwrap(), Some(b"value".to_vec()));
+
+        // Expiring a key in the past makes it unreadable straight away.
+        assert!(sst_storage.expire_at(b"key", 1).unwrap());
+        assert_eq!(sst_storage.ttl(b"key"), Ttl::Missing);
+        assert_eq!(sst_storage.read(b"key").unwrap(), None);
+        assert!(!sst_storage.expire(b"key", Duration::from_secs(60)).unwrap());
+        assert!(!sst_storage.expire(b"missing", Duration::from_secs(60)).unwrap());
+    }
+
+    #[test]
+    fn test_ttl_changes_survive_restart_and_merge() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let (_, one_hour_from_now) = generate_timestamp_range(60);
+
+        let mut sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        sst_storage.write(b"extended", b"value", false, Some(one_hour_from_now)).unwrap();
+        sst_storage.write(b"persisted", b"value", false, Some(one_hour_from_now)).unwrap();
+        sst_storage.write(b"expired", b"value", false, None).unwrap();
+        // The values are sealed before their expiry changes, so the changes are replayed
+        // from the hint file of one segment on top of the values in another.
+        sst_storage.rollover().unwrap();
+        sst_storage.expire_at(b"extended", one_hour_from_now + 60).unwrap();
+        sst_storage.persist(b"persisted").unwrap();
+        sst_storage.expire_at(b"expired", 1).unwrap();
+        sst_storage.rollover().unwrap();
+        drop(sst_storage);
+
+        let check = |sst_storage: &SStStorage<MemFile>| {
+            assert_eq!(sst_storage.index.get(&b"extended"[..]).unwrap().expires_at, Some(one_hour_from_now + 60));
+            assert_eq!(sst_storage.ttl(b"persisted"), Ttl::Persistent);
+            assert_eq!(sst_storage.ttl(b"expired"), Ttl::Missing);
+            assert_eq!(sst_storage.read(b"extended").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(sst_storage.read(b"persisted").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(sst_storage.read(b"expired").unwrap(), None);
+        };
+        let sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        check(&sst_storage);
+
+        // The merge drops the records that changed the expiry, so the values it copies
+        // carry the new expiry themselves.
+        let handle = StorageHandle::new(sst_storage);
+        assert_eq!(handle.merge().unwrap().records_kept, 2);
+        drop(handle);
+        check(&load_storage(dir.clone(), StorageOptions::default()));
+    }
+
+    #[test]
+    fn test_merge_keeps_expiry_changed_while_copying() {
+        let dir = Arc::new(MemSegmentDir::new());
+
+        let mut sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        let plan = sst_storage.plan_merge().unwrap().unwrap();
+        let output = plan.copy().unwrap();
+        sst_storage.expire(b"key", Duration::from_secs(3600)).unwrap();
+        sst_storage.install_merge(output).unwrap();
+
+        // The value moved into the merged segment and kept its new expiry.
+        let entry = *sst_storage.index.get(&b"key"[..]).unwrap();
+        assert!(sst_storage.sealed.contains_key(&entry.file_id));
+        assert!(entry.expires_at.is_some());
+        assert_eq!(sst_storage.read(b"key").unwrap(), Some(b"value".to_vec()));
+        drop(sst_storage);
+
+        let sst_storage = load_storage(dir.clone(), StorageOptions::default());
+        assert_eq!(sst_storage.index.get(&b"key"[..]).unwrap().expires_at, entry.expires_at);
+    }
+
+    #[test]
+    fn test_scan_range_and_reverse() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        for key in ["a", "b", "c", "d", "e"] {
+            sst_storage.write(key.as_bytes(), key.to_uppercase().as_bytes(), false, None).unwrap();
+        }
+        sst_storage.delete_key(b"c").unwrap();
+        sst_storage.write(b"d", b"expired", false, Some(1)).unwrap();
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = sst_storage
+            .scan(Bound::Included(&b"b"[..]), Bound::Unbounded)
+            .collect::<crate::Result<_>>()
+            .unwrap();
+        assert_eq!(pairs, vec![(b"b".to_vec(), b"B".to_vec()), (b"e".to_vec(), b"E".to_vec())]);
+
+        let keys: Vec<&[u8]> = sst_storage
+            .scan(Bound::Unbounded, Bound::Excluded(&b"e"[..]))
+            .keys()
+            .rev()
+            .collect();
+        assert_eq!(keys, vec![&b"b"[..], &b"a"[..]]);
+
+        let last = sst_storage.scan(Bound::Unbounded, Bound::Unbounded).next_back().unwrap().unwrap();
+        assert_eq!(last, (b"e".to_vec(), b"E".to_vec()));
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut sst_storage = SStStorage::new(MemFile::new());
+
+        for key in [&b"us"[..], b"user:1", b"user:2", b"users", b"v", b"\xff", b"\xff\xff", b"\xff\x00"] {
+            sst_storage.write(key, b"value", false, None).unwrap();
+        }
+
+        let keys: Vec<&[u8]> = sst_storage.scan_prefix(b"user").keys().collect();
+        assert_eq!(keys, vec![&b"user:1"[..], b"user:2", b"users"]);
+        let keys: Vec<&[u8]> = sst_storage.scan_prefix(b"user:").keys().rev().collect();
+        assert_eq!(keys, vec![&b"user:2"[..], b"user:1"]);
+        // Prefixes ending in 0xff have no key right after them.
+        let keys: Vec<&[u8]> = sst_storage.scan_prefix(b"\xff").keys().collect();
+        assert_eq!(keys, vec![&b"\xff"[..], b"\xff\x00", b"\xff\xff"]);
+        assert_eq!(sst_storage.scan_prefix(b"").count(), 8);
+        assert_eq!(sst_storage.scan_prefix(b"x").count(), 0);
+    }
+
+    #[test]
+    fn test_db_roundtrip() {
+        let temp_dir = TempDir::new("db_roundtrip");
+        let temp_dir_path = temp_dir.path();
+
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        db.put(b"apple", b"red").unwrap();
+        db.put(b"banana", b"yellow").unwrap();
+        db.put(b"cherry", b"dark red").unwrap();
+        db.delete(b"banana").unwrap();
+        db.put_with_ttl(b"date", b"brown", Duration::from_secs(3600)).unwrap();
+        assert_eq!(db.get(b"apple").unwrap(), Some(b"red".to_vec()));
+        assert_eq!(db.get(b"banana").unwrap(), None);
+        db.close().unwrap();
+
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        assert!(db.torn_tail().is_none());
+        let keys: Vec<Vec<u8>> = db
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"apple".to_vec(), b"cherry".to_vec(), b"date".to_vec()]);
+        assert!(matches!(db.ttl(b"date").unwrap(), Ttl::Expires(_)));
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_db_adopts_the_pre_segment_database_file() {
+        let temp_dir = TempDir::new("db_legacy_file");
+        let temp_dir_path = temp_dir.path();
+        fs::create_dir_all(format!("{}/active", temp_dir_path)).unwrap();
+
+        // Where the database kept its one log file before it was split into segments.
+        let legacy = KeyValue::new(b"legacy_key", b"legacy_value", Some(0), false, 0);
+        fs::write(format!("{}/active/database.txt", temp_dir_path), legacy.to_buffer()).unwrap();
+
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        assert_eq!(db.get(b"legacy_key").unwrap(), Some(b"legacy_value".to_vec()));
+        db.close().unwrap();
+        assert!(!Path::new(temp_dir_path).join("active").exists());
+        assert!(Path::new(temp_dir_path).join("000001.data").exists());
+
+        // A second legacy file next to segments is not merged in silently.
+        fs::create_dir_all(format!("{}/active", temp_dir_path)).unwrap();
+        fs::write(format!("{}/active/database.txt", temp_dir_path), legacy.to_buffer()).unwrap();
+        let error = Db::open(temp_dir_path, StorageOptions::default()).err().unwrap();
+        assert!(error.is_io(io::ErrorKind::AlreadyExists));
+    }
+
+    #[test]
+    fn test_db_merges_an_adopted_database_file_larger_than_a_segment() {
+        let temp_dir = TempDir::new("db_large_legacy_file");
+        let temp_dir_path = temp_dir.path();
+        fs::create_dir_all(format!("{}/active", temp_dir_path)).unwrap();
+        let options = StorageOptions {
+            max_segment_size: 256,
+            ..StorageOptions::default()
+        };
+
+        // The legacy file is adopted whole, however much bigger than a segment it is.
+        let mut legacy = Vec::new();
+        for i in 0..100u8 {
+            legacy.extend(KeyValue::new(&[b'k', i], &[i; 8], Some(0), false, 0).to_buffer());
+        }
+        fs::write(format!("{}/active/database.txt", temp_dir_path), legacy).unwrap();
+
+        let db = Db::open(temp_dir_path, options).unwrap();
+        let stats = db.merge().unwrap();
+        assert_eq!(stats.records_kept, 100);
+        db.put(b"after", b"merge").unwrap();
+        db.close().unwrap();
+
+        let db = Db::open(temp_dir_path, options).unwrap();
+        assert_eq!(db.get(b"after").unwrap(), Some(b"merge".to_vec()));
+        for i in 0..100u8 {
+            assert_eq!(db.get(&[b'k', i]).unwrap(), Some(vec![i; 8]));
+        }
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_db_scan_between_writes() {
+        let temp_dir = TempDir::new("db_scan");
+        let temp_dir_path = temp_dir.path();
+
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            db.put(key.as_bytes(), b"value").unwrap();
+        }
+
+        // The cursor does not hold on to the storage, so writes go through mid-scan and
+        // are seen once the cursor gets to them.
+        let mut cursor = db.scan(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(cursor.next().unwrap().unwrap().0, b"a".to_vec());
+        db.put(b"bb", b"new").unwrap();
+        db.delete(b"c").unwrap();
+        assert_eq!(cursor.next_back().unwrap().unwrap().0, b"d".to_vec());
+        let rest: Vec<Vec<u8>> = cursor.map(|item| item.unwrap().0).collect();
+        assert_eq!(rest, vec![b"b".to_vec(), b"bb".to_vec()]);
+
+        let keys: Vec<Vec<u8>> = db.scan_prefix(b"b").rev().map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, vec![b"bb".to_vec(), b"b".to_vec()]);
+        // A range that ends before it starts is empty.
+        assert_eq!(db.scan(Bound::Included(&b"d"[..]), Bound::Included(&b"a"[..])).count(), 0);
+        assert_eq!(db.scan(Bound::Excluded(&b"b"[..]), Bound::Excluded(&b"b"[..])).count(), 0);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_is_all_or_nothing() {
+        let temp_dir = TempDir::new("write_batch");
+        let temp_dir_path = temp_dir.path();
+        let options = |recovery| StorageOptions {
+            recovery,
+            ..StorageOptions::default()
+        };
+
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let batch_start;
+        {
+            let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Strict));
+            sst_storage.write(b"kept", b"value", false, None).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"1").put(b"b", b"2").delete(b"kept").put(b"a", b"3");
+            sst_storage.write_batch(&batch).unwrap();
+            assert_eq!(sst_storage.read(b"a").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(sst_storage.read(b"kept").unwrap(), None);
+
+            // A committed batch survives a restart, and its markers never reach the keydir.
+            sst_storage.load_db_from_disk().unwrap();
+            assert_eq!(sst_storage.read(b"a").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(sst_storage.read(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(sst_storage.read(b"kept").unwrap(), None);
+            assert_eq!(sst_storage.index.len(), 2);
+
+            batch_start = fs::metadata(&segment_path).unwrap().len();
+            let mut batch = WriteBatch::new();
+            batch.put(b"c", b"4").delete(b"a");
+            sst_storage.write_batch(&batch).unwrap();
+        }
+        let records = read_records_from_file(&segment_path).unwrap();
+        assert_eq!(records.last().unwrap().batch_marker, Some(BatchMarker::Commit));
+
+        // Simulate a crash just before the commit marker of the second batch was written.
+        let mut segment = fs::read(&segment_path).unwrap();
+        let commit_len = encode_record(&records[records.len() - 1], RecordFormat::V2).unwrap().len();
+        segment.truncate(segment.len() - commit_len);
+        let uncommitted_size = segment.len() as u64;
+        fs::write(&segment_path, &segment).unwrap();
+
+        // The records of the batch are all intact, but without the marker none of them count.
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Strict));
+        let torn_tail = sst_storage.load_db_from_disk().unwrap().unwrap();
+        assert_eq!(
+            torn_tail,
+            TornTail {
+                file_id: 1,
+                offset: batch_start,
+                dropped_bytes: uncommitted_size - batch_start,
+            }
+        );
+        assert_eq!(sst_storage.read(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(sst_storage.read(b"c").unwrap(), None);
+        drop(sst_storage);
+
+        // A batch torn halfway through one of its records goes back to where it started.
+        segment.truncate(segment.len() - 3);
+        fs::write(&segment_path, &segment).unwrap();
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options(RecoveryPolicy::Truncate));
+        assert_eq!(sst_storage.load_db_from_disk().unwrap().unwrap().offset, batch_start);
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), batch_start);
+        assert_eq!(sst_storage.read(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(sst_storage.read(b"c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_batch_stays_in_one_segment() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            max_segment_size: 128,
+            ..StorageOptions::default()
+        };
+        let mut sst_storage = SStStorage::open(dir.clone(), options).unwrap();
+        sst_storage.write(b"first", &[0; 64], false, None).unwrap();
+
+        // The batch does not fit after the first record, so all of it goes to the next
+        // segment, even though that makes the segment bigger than the limit.
+        let mut batch = WriteBatch::new();
+        for i in 0..8u8 {
+            batch.put(&[b'k', i], &[i; 16]);
+        }
+        sst_storage.write_batch(&batch).unwrap();
+        assert_eq!(sst_storage.active_id, 2);
+        assert!(sst_storage.index.range::<[u8], _>((Bound::Included(&b"k"[..]), Bound::Unbounded)).all(|(_, entry)| entry.file_id == 2));
+
+        // The hint file of the sealed segment is rebuilt the same way as the segment.
+        sst_storage.rollover().unwrap();
+        sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(sst_storage.index.len(), 9);
+        assert_eq!(sst_storage.read(&[b'k', 7]).unwrap(), Some(vec![7; 16]));
+    }
+
+    #[test]
+    fn test_transaction_commits_unless_a_read_key_changed() {
+        let temp_dir = TempDir::new("transaction");
+        let temp_dir_path = temp_dir.path();
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        db.put(b"alice", b"100").unwrap();
+        db.put(b"bob", b"50").unwrap();
+
+        // Move 30 from alice to bob. The transaction sees its own writes.
+        let mut transaction = db.transaction();
+        let alice = transaction.get(b"alice").unwrap().unwrap();
+        let bob = transaction.get(b"bob").unwrap().unwrap();
+        assert_eq!((alice, bob), (b"100".to_vec(), b"50".to_vec()));
+        transaction.put(b"alice", b"70");
+        transaction.put(b"bob", b"80");
+        transaction.delete(b"carol");
+        assert_eq!(transaction.get(b"alice").unwrap(), Some(b"70".to_vec()));
+        assert_eq!(transaction.get(b"carol").unwrap(), None);
+        assert_eq!(db.get(b"alice").unwrap(), Some(b"100".to_vec()));
+        transaction.commit().unwrap();
+        assert_eq!(db.get(b"alice").unwrap(), Some(b"70".to_vec()));
+        assert_eq!(db.get(b"bob").unwrap(), Some(b"80".to_vec()));
+
+        // Another writer changes a key the transaction read, even to the same value.
+        let mut transaction = db.transaction();
+        transaction.get(b"alice").unwrap();
+        transaction.put(b"bob", b"0");
+        db.put(b"alice", b"70").unwrap();
+        assert!(matches!(
+            transaction.commit(),
+            Err(Error::Conflict { key }) if key == b"alice"
+        ));
+        assert_eq!(db.get(b"bob").unwrap(), Some(b"80".to_vec()));
+
+        // A key that was missing when it was read conflicts once someone creates it.
+        let mut transaction = db.transaction();
+        assert_eq!(transaction.get(b"dave").unwrap(), None);
+        transaction.put(b"dave", b"1");
+        db.put(b"dave", b"2").unwrap();
+        assert!(matches!(transaction.commit(), Err(Error::Conflict { .. })));
+
+        // Writes to keys the transaction never read do not.
+        let mut transaction = db.transaction();
+        transaction.get(b"alice").unwrap();
+        transaction.put(b"bob", b"90");
+        db.put(b"bob", b"60").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(db.get(b"bob").unwrap(), Some(b"90".to_vec()));
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let temp_dir = TempDir::new("conditional");
+        let temp_dir_path = temp_dir.path();
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+
+        assert!(db.put_if_absent(b"leader", b"node-1").unwrap());
+        assert!(!db.put_if_absent(b"leader", b"node-2").unwrap());
+        assert!(!db.update_if_present(b"follower", b"node-2").unwrap());
+        assert_eq!(db.get(b"follower").unwrap(), None);
+        assert!(db.update_if_present(b"leader", b"node-3").unwrap());
+
+        assert!(!db.compare_and_set(b"leader", Some(b"node-1"), b"node-4").unwrap());
+        assert!(db.compare_and_set(b"leader", Some(b"node-3"), b"node-4").unwrap());
+        assert!(!db.compare_and_set(b"term", Some(b"1"), b"2").unwrap());
+        assert!(db.compare_and_set(b"term", None, b"1").unwrap());
+
+        // Rewriting the same value still gives the key a new version.
+        let (_, version) = db.get_with_version(b"term").unwrap().unwrap();
+        db.put(b"term", b"1").unwrap();
+        assert!(!db.compare_and_set_version(b"term", Some(version), b"2").unwrap());
+        let (_, version) = db.get_with_version(b"term").unwrap().unwrap();
+        assert!(db.compare_and_set_version(b"term", Some(version), b"2").unwrap());
+        assert!(!db.compare_and_set_version(b"missing", Some(version), b"2").unwrap());
+        assert!(db.compare_and_set_version(b"missing", None, b"2").unwrap());
+
+        // A conditional write replaces the value, but not the expiry.
+        db.put_with_ttl(b"lease", b"node-1", Duration::from_secs(3600)).unwrap();
+        assert!(db.update_if_present(b"lease", b"node-2").unwrap());
+        assert!(db.compare_and_set(b"lease", Some(b"node-2"), b"node-3").unwrap());
+        let (_, version) = db.get_with_version(b"lease").unwrap().unwrap();
+        assert!(db.compare_and_set_version(b"lease", Some(version), b"node-4").unwrap());
+        assert_eq!(db.get(b"lease").unwrap(), Some(b"node-4".to_vec()));
+        assert!(matches!(db.ttl(b"lease").unwrap(), Ttl::Expires(ttl) if ttl > Duration::from_secs(3500)));
+
+        assert!(!db.delete_if_equals(b"leader", b"node-1").unwrap());
+        assert!(db.delete_if_equals(b"leader", b"node-4").unwrap());
+        assert!(!db.delete_if_equals(b"leader", b"node-4").unwrap());
+        assert_eq!(db.get(b"leader").unwrap(), None);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_set_keeps_a_counter_consistent() {
+        let storage = StorageHandle::new(SStStorage::new(MemFile::new()));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        loop {
+                            let current = storage.get_with_version(b"counter").unwrap();
+                            let (count, version) = match current {
+                                Some((value, version)) => {
+                                    (u64::from_le_bytes(value.try_into().unwrap()), Some(version))
+                                }
+                                None => (0, None),
+                            };
+                            let next = (count + 1).to_le_bytes();
+                            if storage.compare_and_set_version(b"counter", version, &next).unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let value = storage.read(b"counter").unwrap().unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 200);
+    }
+
+    #[test]
+    fn test_snapshot_reads_are_repeatable_and_survive_merge() {
+        let temp_dir = TempDir::new("snapshot");
+        let temp_dir_path = temp_dir.path();
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.put_with_ttl(b"c", b"3", Duration::from_secs(3600)).unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        db.put(b"a", b"changed").unwrap();
+        db.delete(b"b").unwrap();
+        db.put(b"d", b"4").unwrap();
+        db.persist(b"c").unwrap();
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(snapshot.get(b"d").unwrap(), None);
+        assert!(matches!(snapshot.ttl(b"c"), Ttl::Expires(_)));
+        assert_eq!(db.ttl(b"c").unwrap(), Ttl::Persistent);
+
+        // The merge rewrites segment 1, which the snapshot reads from, so it stays on disk.
+        let segment_path = format!("{}/000001.data", temp_dir_path);
+        let stats = db.merge().unwrap();
+        assert_eq!(stats.segments_merged, 1);
+        assert!(fs::metadata(&segment_path).is_ok());
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .collect::<crate::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec()),
+            ]
+        );
+        assert_eq!(db.get(b"a").unwrap(), Some(b"changed".to_vec()));
+
+        // A second snapshot taken after the merge does not hold on to segment 1.
+        let later = db.snapshot().unwrap();
+        drop(snapshot);
+        assert!(fs::metadata(&segment_path).is_err());
+        assert_eq!(later.get(b"d").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(later.scan_prefix(b"").count(), 3);
+        drop(later);
+        db.close().unwrap();
+
+        // The storage reloads the same as before.
+        let db = Db::open(temp_dir_path, StorageOptions::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_storage_reloads_merges_and_truncates() {
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = |recovery| StorageOptions {
+            max_segment_size: 64,
+            recovery,
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = load_storage(dir.clone(), options(RecoveryPolicy::Strict));
+        for i in 0..20u8 {
+            sst_storage.write(&[b'k', i % 5], &[i; 8], false, None).unwrap();
+        }
+        sst_storage.delete_key(&[b'k', 0]).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"batch", b"value");
+        sst_storage.write_batch(&batch).unwrap();
+        assert!(dir.segment_ids().unwrap().len() > 1);
+        drop(sst_storage);
+
+        // Reloaded from the segments and their hint files.
+        let mut sst_storage = open_storage(dir.clone(), options(RecoveryPolicy::Strict));
+        assert_eq!(sst_storage.load_db_from_disk().unwrap(), None);
+        assert_eq!(sst_storage.read(&[b'k', 0]).unwrap(), None);
+        assert_eq!(sst_storage.read(&[b'k', 4]).unwrap(), Some(vec![19; 8]));
+        assert_eq!(sst_storage.read(b"batch").unwrap(), Some(b"value".to_vec()));
+
+        let plan = sst_storage.plan_merge().unwrap().unwrap();
+        let stats = sst_storage.install_merge(plan.copy().unwrap()).unwrap();
+        assert_eq!(stats.records_kept, 5);
+        sst_storage.write(b"after", b"merge", false, None).unwrap();
+        let active_id = sst_storage.active_id;
+        drop(sst_storage);
+
+        // Simulate a crash halfway through appending the last record.
+        let mut active = dir.open_segment(active_id).unwrap();
+        let len = active.len().unwrap();
+        active.set_len(len - 3).unwrap();
+        let mut sst_storage = open_storage(dir.clone(), options(RecoveryPolicy::Truncate));
+        let torn_tail = sst_storage.load_db_from_disk().unwrap();
+        assert_eq!(torn_tail.unwrap().file_id, active_id);
+        assert_eq!(sst_storage.read(b"after").unwrap(), None);
+        assert_eq!(sst_storage.read(&[b'k', 1]).unwrap(), Some(vec![16; 8]));
+        assert_eq!(sst_storage.scan(Bound::Unbounded, Bound::Unbounded).count(), 5);
+    }
+
+    #[test]
+    fn test_in_memory_db() {
+        let db = Db::in_memory(StorageOptions::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put_with_ttl(b"b", b"2", Duration::from_secs(3600)).unwrap();
+        assert!(db.put_if_absent(b"c", b"3").unwrap());
+        let snapshot = db.snapshot().unwrap();
+        db.delete(b"a").unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            db.get_many(&[b"c", b"a", b"b"]).unwrap(),
+            vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]
+        );
+        let keys: Vec<Vec<u8>> = db.scan_prefix(b"").map(|item| item.unwrap().0).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_crash_consistency_under_injected_faults() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        type State = BTreeMap<Vec<u8>, Vec<u8>>;
+        // The puts (`Some`) and deletes (`None`) of the operation that failed, which may
+        // or may not have reached the disk before the crash.
+        type Changes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+        fn apply(state: &State, changes: &Changes) -> State {
+            let mut state = state.clone();
+            for (key, value) in changes {
+                match value {
+                    Some(value) => state.insert(key.clone(), value.clone()),
+                    None => state.remove(key),
+                };
+            }
+            state
+        }
+
+        let mut rng = StdRng::seed_from_u64(22);
+        let mem_dir = Arc::new(MemSegmentDir::new());
+        // Everything that was written and acknowledged before the last crash.
+        let mut acked = State::new();
+        let mut failed: Option<Changes> = None;
+
+        for round in 0..100 {
+            let injector = Arc::new(FaultInjector::new());
+            let dir = FaultyDir::new(mem_dir.clone(), injector.clone());
+            let options = StorageOptions {
+                max_segment_size: 256,
+                recovery: RecoveryPolicy::Truncate,
+                sync: SyncPolicy::Always,
+                ..StorageOptions::default()
+            };
+            let mut sst_storage = SStStorage::open(Arc::new(dir), options).unwrap();
+            sst_storage.load_db_from_disk().unwrap();
+
+            // The failed operation is either all there or not there at all.
+            let state: State = sst_storage
+                .scan(Bound::Unbounded, Bound::Unbounded)
+                .map(|item| item.unwrap())
+                .collect();
+            let with_failed = apply(&acked, &failed.take().unwrap_or_default());
+            assert!(
+                state == acked || state == with_failed,
+                "round {}: {:?} is neither {:?} nor {:?}",
+                round,
+                state,
+                acked,
+                with_failed
+            );
+            acked = state;
+
+            // A corrupted read is reported, never returned as a value.
+            if let Some(key) = acked.keys().nth(rng.gen_range(0..acked.len().max(1))) {
+                injector.flip_next_read();
+                assert!(sst_storage.read(key).is_err(), "round {}", round);
+            }
+
+            let fail_syncs_at = rng.gen_range(0..40);
+            if rng.gen_bool(0.5) {
+                injector.fail_writes_after(rng.gen_range(0..2000));
+            }
+            for op in 0..30 {
+                injector.fail_syncs(op == fail_syncs_at);
+                let key = |rng: &mut StdRng| vec![b'k', rng.gen_range(0..10)];
+                let value = |rng: &mut StdRng| vec![rng.gen(); rng.gen_range(0..40)];
+                let mut changes = Changes::new();
+                let result = match rng.gen_range(0..10) {
+                    0..=4 => {
+                        let (key, value) = (key(&mut rng), value(&mut rng));
+                        changes.insert(key.clone(), Some(value.clone()));
+                        sst_storage.write(&key, &value, false, None)
+                    }
+                    5..=6 => {
+                        let key = key(&mut rng);
+                        changes.insert(key.clone(), None);
+                        sst_storage.delete_key(&key)
+                    }
+                    7..=8 => {
+                        let mut batch = WriteBatch::new();
+                        for _ in 0..rng.gen_range(1..5) {
+                            let key = key(&mut rng);
+                            if rng.gen_bool(0.7) {
+                                let value = value(&mut rng);
+                                batch.put(&key, &value);
+                                changes.insert(key, Some(value));
+                            } else {
+                                batch.delete(&key);
+                                changes.insert(key, None);
+                            }
+                        }
+                        sst_storage.write_batch(&batch)
+                    }
+                    // A merge changes no keys, so there is nothing to record.
+                    _ => sst_storage.plan_merge().and_then(|plan| match plan {
+                        Some(plan) => sst_storage.install_merge(plan.copy()?).map(drop),
+                        None => Ok(()),
+                    }),
+                };
+                match result {
+                    Ok(()) => acked = apply(&acked, &changes),
+                    Err(_) => {
+                        failed = Some(changes);
+                        break;
+                    }
+                }
+            }
+            injector.crash_keeping(rng.gen_range(0..300)).unwrap();
+            // Nothing works on the storage after the crash, not even a read.
+            if let Some(key) = acked.keys().next() {
+                assert!(sst_storage.read(key).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmap_reads_survive_rollover_merge_and_reopen() {
+        let temp_dir = TempDir::new("mmap");
+        let temp_dir_path = temp_dir.path();
+        let options = StorageOptions {
+            max_segment_size: 128,
+            read_path: ReadPath::Mmap,
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = load_storage(fs_dir(temp_dir_path), options);
+        for i in 0..30u8 {
+            sst_storage.write(&[b'k', i % 10], &[i; 16], false, None).unwrap();
+        }
+        // Every sealed segment is mapped as it is sealed, and the active one is not.
+        let sealed: Vec<u32> = sst_storage.sealed.keys().copied().collect();
+        assert_eq!(sst_storage.maps.keys().copied().collect::<Vec<_>>(), sealed);
+        assert!(!sst_storage.maps.contains_key(&sst_storage.active_id));
+        for i in 0..10u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(vec![i + 20; 16]));
+        }
+
+        // The merge inputs stay mapped for the snapshot until it is dropped.
+        let snapshot = sst_storage.snapshot().unwrap();
+        let plan = sst_storage.plan_merge().unwrap().unwrap();
+        sst_storage.install_merge(plan.copy().unwrap()).unwrap();
+        assert!(sealed.iter().all(|id| !sst_storage.maps.contains_key(id)));
+        assert!(!sst_storage.maps.is_empty());
+        for i in 0..10u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(vec![i + 20; 16]));
+            assert_eq!(snapshot.get(&[b'k', i]).unwrap(), Some(vec![i + 20; 16]));
+        }
+        drop(snapshot);
+        sst_storage.write(b"after", b"merge", false, None).unwrap();
+        drop(sst_storage);
+
+        let sst_storage = load_storage(fs_dir(temp_dir_path), options);
+        assert_eq!(sst_storage.maps.len(), sst_storage.sealed.len());
+        for i in 0..10u8 {
+            assert_eq!(sst_storage.read(&[b'k', i]).unwrap(), Some(vec![i + 20; 16]));
+        }
+        assert_eq!(sst_storage.read(b"after").unwrap(), Some(b"merge".to_vec()));
+    }
+
+    #[test]
+    fn test_sealed_segments_are_not_written_to() {
+        let temp_dir = TempDir::new("sealed_unwritten");
+        let temp_dir_path = temp_dir.path();
+        let options = StorageOptions {
+            read_path: ReadPath::Mmap,
+            ..StorageOptions::default()
+        };
+        let segment_len = |id: u32| {
+            fs::metadata(format!("{}/{:06}.data", temp_dir_path, id))
+                .unwrap()
+                .len()
+        };
+
+        // An active segment sealed before anything was written to it still gets its
+        // header first.
+        let mut sst_storage = open_storage(fs_dir(temp_dir_path), options);
+        sst_storage.rollover().unwrap();
+        assert_eq!(segment_len(1), SEGMENT_HEADER_LEN as u64);
+        sst_storage.write(b"key", b"value", false, None).unwrap();
+        drop(sst_storage);
+
+        // An empty sealed segment, as an older build could leave, is mapped on open and
+        // stays empty.
+        fs::write(format!("{}/000001.data", temp_dir_path), b"").unwrap();
+        let sst_storage = load_storage(fs_dir(temp_dir_path), options);
+        assert!(sst_storage.maps.contains_key(&1));
+        assert_eq!(segment_len(1), 0);
+        assert_eq!(sst_storage.read(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn test_uring_backend_with_and_without_a_ring() {
+        use crate::UringSegmentDir;
+
+        for blocking in [false, true] {
+            let temp_dir = TempDir::new(&format!("uring_{}", blocking));
+            let temp_dir_path = temp_dir.path();
+            let open = || {
+                let dir = if blocking {
+                    UringSegmentDir::blocking(temp_dir_path).unwrap()
+                } else {
+                    UringSegmentDir::new(temp_dir_path).unwrap()
+                };
+                if blocking {
+                    assert!(!dir.uses_io_uring());
+                }
+                let options = StorageOptions {
+                    max_segment_size: 256,
+                    sync: SyncPolicy::Always,
+                    ..StorageOptions::default()
+                };
+                load_storage(Arc::new(dir), options)
+            };
+
+            let mut sst_storage = open();
+            for i in 0..40u8 {
+                sst_storage.write(&[b'k', i], &[i; 24], false, None).unwrap();
+            }
+            let mut batch = WriteBatch::new();
+            batch.put(b"batch", b"value").delete(&[b'k', 0]);
+            sst_storage.write_batch(&batch).unwrap();
+            assert!(sst_storage.sealed.len() > 1);
+            drop(sst_storage);
+
+            let sst_storage = open();
+            let keys: Vec<Vec<u8>> = (0..40u8).map(|i| vec![b'k', i]).collect();
+            let mut wanted: Vec<&[u8]> = keys.iter().rev().map(Vec::as_slice).collect();
+            wanted.push(b"missing");
+            let values = sst_storage.get_many(&wanted).unwrap();
+            assert_eq!(values.len(), 41);
+            for (value, key) in values.iter().zip(&wanted) {
+                let expected = match *key {
+                    [b'k', 0] | b"missing" => None,
+                    [b'k', i] => Some(vec![*i; 24]),
+                    _ => unreachable!(),
+                };
+                assert_eq!(value, &expected);
+            }
+            assert_eq!(sst_storage.read(b"batch").unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_compressed_values_are_read_transparently() {
+        let json = br#"{"user":"someone","roles":["reader","writer"],"active":true}"#.repeat(20);
+        let short = br#"{"id":1}"#.to_vec();
+        // Random bytes only get longer compressed, so they are stored as they are.
+        let random: Vec<u8> = (0..512).map(|_| rand::random()).collect();
+
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let dir = Arc::new(MemSegmentDir::new());
+            let options = |compression| StorageOptions {
+                max_segment_size: 1024,
+                compression,
+                ..StorageOptions::default()
+            };
+
+            let mut sst_storage = load_storage(dir.clone(), options(Some(Compression::new(codec))));
+            for i in 0..5u8 {
+                sst_storage.write(&[b'j', i], &json, false, None).unwrap();
+            }
+            sst_storage.write(b"short", &short, false, None).unwrap();
+            sst_storage.write(b"random", &random, false, None).unwrap();
+            let stats = sst_storage.compression_stats();
+            assert_eq!((stats.records, stats.records_compressed), (7, 5));
+            assert!(stats.ratio() > 2.0, "{:?} {:?}", codec, stats);
+            assert_eq!(sst_storage.read(&[b'j', 0]).unwrap(), Some(json.clone()));
+            let active = dir.open_segment(sst_storage.active_id).unwrap();
+            let records = read_records(&active.contents().unwrap()).unwrap();
+            assert_eq!(records.last().unwrap().value, random);
+
+            // Rewritten by a merge with a new expiry, and still compressed.
+            sst_storage.rollover().unwrap();
+            assert!(sst_storage.expire(&[b'j', 0], Duration::from_secs(3600)).unwrap());
+            let expires_at = sst_storage.index[&vec![b'j', 0]].expires_at;
+            let plan = sst_storage.plan_merge().unwrap().unwrap();
+            sst_storage.install_merge(plan.copy().unwrap()).unwrap();
+            let entry = sst_storage.index[&vec![b'j', 0]];
+            assert!(entry.length < json.len() as u64);
+            drop(sst_storage);
+
+            // Storage opened without compression still reads compressed values.
+            let mut sst_storage = load_storage(dir.clone(), options(None));
+            assert_eq!(sst_storage.compression_stats(), Default::default());
+            for i in 0..5u8 {
+                assert_eq!(sst_storage.read(&[b'j', i]).unwrap(), Some(json.clone()));
+            }
+            assert_eq!(sst_storage.read(b"short").unwrap(), Some(short.clone()));
+            assert_eq!(sst_storage.read(b"random").unwrap(), Some(random.clone()));
+            assert_eq!(sst_storage.index[&vec![b'j', 0]].expires_at, expires_at);
+            assert!(expires_at.is_some());
+
+            // A merge that only has to change the expiry keeps the value compressed, even
+            // when the storage itself no longer compresses.
+            sst_storage.rollover().unwrap();
+            assert!(sst_storage.expire(&[b'j', 1], Duration::from_secs(3600)).unwrap());
+            let plan = sst_storage.plan_merge().unwrap().unwrap();
+            sst_storage.install_merge(plan.copy().unwrap()).unwrap();
+            let entry = sst_storage.index[&vec![b'j', 1]];
+            assert!(entry.length < json.len() as u64);
+            assert!(entry.expires_at.is_some());
+            drop(sst_storage);
+
+            let sst_storage = load_storage(dir.clone(), options(None));
+            assert_eq!(sst_storage.read(&[b'j', 1]).unwrap(), Some(json.clone()));
+            assert_eq!(sst_storage.index[&vec![b'j', 1]].expires_at, entry.expires_at);
+        }
+        assert_eq!("zstd:128".parse(), Ok(Compression { codec: Codec::Zstd, min_size: 128 }));
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_compressed_records_replay_without_hint_files() {
+        let json = br#"{"user":"someone","roles":["reader","writer"]}"#.repeat(10);
+        let dir = Arc::new(MemSegmentDir::new());
+        let options = StorageOptions {
+            compression: Some(Compression::new(Codec::Lz4)),
+            ..StorageOptions::default()
+        };
+
+        let mut sst_storage = load_storage(dir.clone(), options);
+        sst_storage.write(b"a", &json, false, None).unwrap();
+        sst_storage.write(b"b", b"small", false, None).unwrap();
+        let sealed_id = sst_storage.active_id;
+        sst_storage.rollover().unwrap();
+        sst_storage.write(b"c", &json, false, None).unwrap();
+        sst_storage.write(b"d", b"small", false, None).unwrap();
+        assert_eq!(sst_storage.compression_stats().records_compressed, 2);
+        drop(sst_storage);
+
+        // Both segments are replayed record by record: the sealed one has lost its hint
+        // file and the active one never has one.
+        dir.remove_hint(sealed_id).unwrap();
+        let mut sst_storage = load_storage(dir.clone(), options);
+        for key in [b"a", b"c"] {
+            assert_eq!(sst_storage.read(key).unwrap(), Some(json.clone()));
+        }
+        for key in [b"b", b"d"] {
+            assert_eq!(sst_storage.read(key).unwrap(), Some(b"small".to_vec()));
+        }
+        sst_storage.write(b"e", b"after reopen", false, None).unwrap();
+        drop(sst_storage);
+
+        let sst_storage = load_storage(dir.clone(), options);
+        assert_eq!(sst_storage.read(b"e").unwrap(), Some(b"after reopen".to_vec()));
+        assert_eq!(sst_storage.read(b"c").unwrap(), Some(json));
+    }
+}
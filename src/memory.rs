@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, SeekFrom},
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{segment::SegmentDir, FileIO};
+
+/// A file that lives in memory, for tests and for databases that do not need to
+/// outlive the process.
+///
+/// Like a file on disk, every handle from `try_clone` sees the same contents, and
+/// each has its own cursor for `write`.
+#[derive(Debug, Default)]
+pub struct MemFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    // Where `write` puts the next bytes.
+    position: u64,
+}
+
+impl MemFile {
+    pub fn new() -> Self {
+        MemFile::default()
+    }
+
+    /// A copy of everything in the file.
+    pub fn contents(&self) -> io::Result<Vec<u8>> {
+        Ok(self.data()?.clone())
+    }
+
+    fn data(&self) -> io::Result<RwLockReadGuard<'_, Vec<u8>>> {
+        self.data.read().map_err(|_| poisoned())
+    }
+
+    fn data_mut(&self) -> io::Result<RwLockWriteGuard<'_, Vec<u8>>> {
+        self.data.write().map_err(|_| poisoned())
+    }
+}
+
+impl FileIO for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_at(buf, self.position)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(delta) => (self.len()?, delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        self.position = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative offset")
+        })?;
+        Ok(self.position)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.data_mut()?.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data()?;
+        let start = offset as usize;
+        match data.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )),
+        }
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.data_mut()?;
+        let start = offset as usize;
+        let end = start + buf.len();
+        // Writing past the end leaves a gap of zeroes, as it does in a file.
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data()?.len() as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(MemFile {
+            data: self.data.clone(),
+            position: self.position,
+        })
+    }
+}
+
+/// A data directory of `MemFile`s.
+///
+/// Storage opened on it again, through another `Arc` to the same directory, reloads
+/// whatever was written before. Removing a file only drops it from the directory;
+/// handles that are still open can read it until they are dropped.
+#[derive(Default)]
+pub struct MemSegmentDir {
+    files: Mutex<MemFiles>,
+}
+
+#[derive(Default)]
+struct MemFiles {
+    segments: BTreeMap<u32, MemFile>,
+    merges: BTreeMap<u32, MemFile>,
+    hints: BTreeMap<u32, MemFile>,
+}
+
+impl MemSegmentDir {
+    pub fn new() -> Self {
+        MemSegmentDir::default()
+    }
+
+    fn files(&self) -> io::Result<MutexGuard<'_, MemFiles>> {
+        self.files.lock().map_err(|_| poisoned())
+    }
+}
+
+impl SegmentDir<MemFile> for MemSegmentDir {
+    fn open_segment(&self, id: u32) -> io::Result<MemFile> {
+        self.files()?.segments.entry(id).or_default().try_clone()
+    }
+
+    fn segment_ids(&self) -> io::Result<Vec<u32>> {
+        Ok(self.files()?.segments.keys().copied().collect())
+    }
+
+    fn remove_segment(&self, id: u32) -> io::Result<()> {
+        self.files()?.segments.remove(&id);
+        Ok(())
+    }
+
+    fn create_merge_segment(&self, id: u32) -> io::Result<MemFile> {
+        let file = MemFile::new();
+        self.files()?.merges.insert(id, file.try_clone()?);
+        Ok(file)
+    }
+
+    fn commit_merge_segment(&self, id: u32) -> io::Result<()> {
+        let mut files = self.files()?;
+        let file = files.merges.remove(&id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No merge output for segment {}", id),
+            )
+        })?;
+        files.segments.insert(id, file);
+        Ok(())
+    }
+
+    fn remove_uncommitted_merges(&self) -> io::Result<()> {
+        self.files()?.merges.clear();
+        Ok(())
+    }
+
+    fn create_hint(&self, id: u32) -> io::Result<MemFile> {
+        let file = MemFile::new();
+        self.files()?.hints.insert(id, file.try_clone()?);
+        Ok(file)
+    }
+
+    fn open_hint(&self, id: u32) -> io::Result<Option<MemFile>> {
+        let files = self.files()?;
+        match files.hints.get(&id) {
+            Some(file) => Ok(Some(MemFile {
+                data: file.data.clone(),
+                position: 0,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_hint(&self, id: u32) -> io::Result<()> {
+        self.files()?.hints.remove(&id);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("In-memory file lock poisoned: a thread panicked while holding it")
+}
@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, io, sync::Arc};
+
+use crate::{
+    encode_record_with, hint::HintEntry, parse_record_from_buffer, rewrite_expiry_v2,
+    segment::SegmentDir, unix_now, Compression, FileIO, KeyDirEntry, Record, RecordFormat,
+    Result, SStStorage, SegmentHeader, SEGMENT_HEADER_LEN,
+};
+
+/// What a merge rewrote and how much space it gave back.
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeStats {
+    pub segments_merged: usize,
+    pub records_kept: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// A merge whose inputs have been sealed and whose output ids have been reserved.
+///
+/// It holds its own handles to the inputs, so the records can be copied without
+/// access to the storage while writes carry on.
+pub(crate) struct MergePlan<T: FileIO> {
+    dir: Arc<dyn SegmentDir<T>>,
+    inputs: Vec<u32>,
+    first_output_id: u32,
+    reserved_ids: u32,
+    // The keydir entries that pointed into the inputs when the merge started, in
+    // on-disk order.
+    live: Vec<(Vec<u8>, KeyDirEntry)>,
+    segments: BTreeMap<u32, (T, SegmentHeader)>,
+    max_segment_size: u64,
+    // Applied to the records from legacy segments, which are converted to the current
+    // record format.
+    compression: Option<Compression>,
+}
+
+/// The merge outputs, written out but not yet committed.
+pub(crate) struct MergeOutput<T: FileIO> {
+    dir: Arc<dyn SegmentDir<T>>,
+    inputs: Vec<u32>,
+    header: SegmentHeader,
+    outputs: Vec<(u32, T)>,
+    // Each copied record as (key, where it was, where it is now).
+    relocated: Vec<(Vec<u8>, KeyDirEntry, KeyDirEntry)>,
+    // Records that had expired and were not copied.
+    expired: Vec<(Vec<u8>, KeyDirEntry)>,
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Starts a merge, which rewrites the live, unexpired records of every sealed
+    /// segment into new segments and deletes the old ones. Overwritten values,
+    /// tombstones and expired records are left behind. Returns `None` for a storage
+    /// that wraps a single file.
+    ///
+    /// The active segment is sealed first, and the new active segment skips past the
+    /// ids handed to the merge output. Merged segments therefore sort after everything
+    /// they replace and before everything written later, which is the order
+    /// `load_db_from_disk` replays them in.
+    pub(crate) fn plan_merge(&mut self) -> Result<Option<MergePlan<T>>> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+
+        let mut inputs: Vec<u32> = self.sealed.keys().copied().collect();
+        inputs.push(self.active_id);
+        let mut headers = BTreeMap::new();
+        for &id in &inputs {
+            headers.insert(id, self.segment_header(id)?);
+        }
+
+        // Everything the keydir points at is live, and all of it is in the inputs. Copy it
+        // in on-disk order so the inputs are read sequentially.
+        let mut live: Vec<(Vec<u8>, KeyDirEntry)> = self
+            .index
+            .iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        live.sort_by_key(|(_, entry)| (entry.file_id, entry.offset));
+
+        // Records can grow when they are rewritten, so how many outputs they fill depends
+        // on the records rather than on how many inputs they come from.
+        let first_output_id = self.active_id + 1;
+        let reserved_ids = max_outputs(
+            live.iter().map(|(_, entry)| {
+                entry.length + max_growth(headers[&entry.file_id].record_format())
+            }),
+            self.options.max_segment_size,
+        );
+        self.rollover_to(first_output_id + reserved_ids)?;
+
+        let mut segments = BTreeMap::new();
+        for (id, header) in headers {
+            segments.insert(id, (self.segment(id)?.try_clone()?, header));
+        }
+
+        Ok(Some(MergePlan {
+            dir,
+            inputs,
+            first_output_id,
+            reserved_ids,
+            live,
+            segments,
+            max_segment_size: self.options.max_segment_size,
+            compression: self.options.compression,
+        }))
+    }
+
+    /// Commits the outputs of a merge and deletes its inputs.
+    ///
+    /// Keys written or deleted since the merge was planned keep their newer entry: a
+    /// copied record only replaces the keydir entry that still points where it was
+    /// copied from.
+    pub(crate) fn install_merge(&mut self, merge: MergeOutput<T>) -> Result<MergeStats> {
+        let MergeOutput {
+            dir,
+            inputs,
+            header,
+            outputs,
+            relocated,
+            expired,
+        } = merge;
+        let segments_merged = inputs.len();
+        let records_kept = relocated.len();
+        let mut output_bytes = 0;
+        let mut input_bytes = 0;
+
+        // Swap the merged segments in. Until the inputs are deleted both copies exist, and
+        // replaying them in id order gives the same result as replaying the inputs alone.
+        for (id, mut output) in outputs {
+            output_bytes += output.len()?;
+            // The inputs are about to be deleted, so the output must be on disk first
+            // whatever the sync policy says.
+            output.sync()?;
+            dir.commit_merge_segment(id)?;
+            self.sealed.insert(id, output);
+            self.map_sealed(id)?;
+            self.headers.insert(id, header);
+
+            let hints: Vec<HintEntry> = relocated
+                .iter()
+                .filter(|(_, _, entry)| entry.file_id == id)
+                .map(|(key, _, entry)| HintEntry {
+                    key: key.clone(),
+                    offset: entry.offset,
+                    length: entry.length,
+                    write_time: entry.write_time,
+                    expires_at: entry.expires_at,
+                    tombstone: false,
+                    expiry_update: false,
+                })
+                .collect();
+            self.write_hint_file(id, &hints)?;
+        }
+        dir.sync()?;
+        for (key, from, to) in relocated {
+            // The expiry may have changed since the copy without the value moving, and the
+            // record that changed it is replayed after the merged segments.
+            if let Some(entry) = self.index.get_mut(&key) {
+                if (entry.file_id, entry.offset) == (from.file_id, from.offset) {
+                    *entry = KeyDirEntry {
+                        expires_at: entry.expires_at,
+                        ..to
+                    };
+                }
+            }
+        }
+        for (key, entry) in expired {
+            if self.index.get(&key) == Some(&entry) {
+                self.index.remove(&key);
+            }
+        }
+        for &id in &inputs {
+            if let Some(input) = self.sealed.remove(&id) {
+                input_bytes += input.len()?;
+            }
+            // Snapshots that read the input keep their own reference to the map.
+            self.maps.remove(&id);
+            self.headers.remove(&id);
+        }
+        // The files go once no snapshot reads from them, which may be right away.
+        self.snapshots.retire(inputs)?;
+
+        Ok(MergeStats {
+            segments_merged,
+            records_kept,
+            bytes_reclaimed: input_bytes.saturating_sub(output_bytes),
+        })
+    }
+}
+
+impl<T: FileIO> MergePlan<T> {
+    /// Copies the planned records into uncommitted merge outputs. If that fails, the
+    /// outputs written so far are deleted and the inputs stay as they are.
+    pub(crate) fn copy(self) -> Result<MergeOutput<T>> {
+        let dir = self.dir.clone();
+        self.copy_records().inspect_err(|_| {
+            if let Err(e) = dir.remove_uncommitted_merges() {
+                log::warn!("Failed to delete the outputs of a failed merge: {}", e);
+            }
+        })
+    }
+
+    fn copy_records(self) -> Result<MergeOutput<T>> {
+        let now = unix_now();
+        let header = SegmentHeader::new(now);
+        let mut outputs: Vec<(u32, T)> = Vec::new();
+        let mut output_size = 0;
+        let mut relocated = Vec::with_capacity(self.live.len());
+        let mut expired = Vec::new();
+        for (key, entry) in self.live {
+            if entry.is_expired(now) {
+                expired.push((key, entry));
+                continue;
+            }
+
+            let (file, input_header) = &self.segments[&entry.file_id];
+            let format = input_header.record_format();
+            let mut buffer = vec![0; entry.length as usize];
+            file.read_at(&mut buffer, entry.offset)?;
+            // Merged segments always have a current header, so records from legacy segments
+            // are converted. A value whose expiry was changed afterwards is rewritten with
+            // the new one, because the record that changed it is not copied. Only the expiry
+            // changes then, and the value stays stored the way it was written.
+            let record = parse_record_from_buffer(&buffer, format)
+                .map_err(|e| e.at(Some(entry.file_id), entry.offset))?;
+            if format != header.record_format() {
+                let record = Record {
+                    expires_at: entry.expires_at,
+                    ..record
+                };
+                buffer = encode_record_with(&record, header.record_format(), self.compression)?;
+            } else if record.expires_at != entry.expires_at {
+                buffer = rewrite_expiry_v2(&buffer, entry.expires_at)?;
+            }
+            let length = buffer.len() as u64;
+
+            let data_start = header.data_start();
+            if outputs.is_empty()
+                || (output_size > data_start && output_size + length > self.max_segment_size)
+            {
+                let id = self.first_output_id + outputs.len() as u32;
+                // The next id belongs to the active segment.
+                if id >= self.first_output_id + self.reserved_ids {
+                    return Err(io::Error::other(format!(
+                        "Merge needs more than the {} segment ids it reserved",
+                        self.reserved_ids
+                    ))
+                    .into());
+                }
+                let mut output = self.dir.create_merge_segment(id)?;
+                output.write(&header.encode())?;
+                outputs.push((id, output));
+                output_size = data_start;
+            }
+            let (output_id, output) = outputs.last_mut().expect("an output was just created");
+            output.write(&buffer)?;
+            relocated.push((
+                key,
+                entry,
+                KeyDirEntry {
+                    file_id: *output_id,
+                    offset: output_size,
+                    length,
+                    ..entry
+                },
+            ));
+            output_size += length;
+        }
+
+        Ok(MergeOutput {
+            dir: self.dir,
+            inputs: self.inputs,
+            header,
+            outputs,
+            relocated,
+            expired,
+        })
+    }
+}
+
+// The most a record grows by when a merge rewrites it. A v2 record only ever gains an
+// expiry. A v1 record is converted to v2, which adds a flags byte, up to two more
+// length bytes and an expiry, and drops the tombstone byte.
+fn max_growth(format: RecordFormat) -> u64 {
+    match format {
+        RecordFormat::V1 => 10,
+        RecordFormat::V2 => 8,
+    }
+}
+
+// How many outputs records of at most the given lengths can fill. No output is empty,
+// and an output is only started when the one before it cannot take the next record,
+// so every two outputs in a row hold more than a segment's worth of records.
+fn max_outputs(lengths: impl Iterator<Item = u64>, max_segment_size: u64) -> u32 {
+    let (records, total) = lengths.fold((0u64, 0u64), |(records, total), length| {
+        (records + 1, total + length)
+    });
+    let capacity = max_segment_size.saturating_sub(SEGMENT_HEADER_LEN as u64);
+    let by_size = match capacity {
+        0 => records,
+        capacity => 2 * (total / capacity) + 1,
+    };
+    by_size.min(records).max(1) as u32
+}
@@ -0,0 +1,77 @@
+use std::{fs::File, io, ops::Deref, str::FromStr, sync::Arc};
+
+use memmap2::Mmap;
+
+use crate::{FileIO, KeyDirEntry, SStStorage};
+
+/// How records are read from sealed segments. The active segment is always read
+/// from the file, since it is still being appended to.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReadPath {
+    /// A positioned read into a new buffer for every record.
+    #[default]
+    File,
+    /// Slices of a read-only memory map of each sealed segment, with no system call
+    /// per read. Backends that cannot be mapped fall back to `File`.
+    Mmap,
+}
+
+impl FromStr for ReadPath {
+    type Err = String;
+
+    /// Parses `file` or `mmap`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(ReadPath::File),
+            "mmap" => Ok(ReadPath::Mmap),
+            _ => Err(format!("Unknown read path {:?}", s)),
+        }
+    }
+}
+
+/// A sealed segment mapped into memory, from `FileIO::map`.
+pub struct SegmentMap(Mmap);
+
+impl Deref for SegmentMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Maps a file on disk.
+pub(crate) fn map_file(file: &File) -> io::Result<SegmentMap> {
+    // SAFETY: only sealed segments are mapped, and nothing writes to or truncates those
+    // again. A segment gets its header while it is still active, and appends, header
+    // writes and torn-tail truncation only ever touch the active segment. The directory
+    // lock keeps other processes from writing to them, and a merge that deletes one
+    // leaves the mapping pointing at the unlinked file until it is dropped.
+    let map = unsafe { Mmap::map(file)? };
+    Ok(SegmentMap(map))
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Maps a segment that has just been sealed, if the options ask for it and its
+    /// backend supports it. Replaces whatever map the id had before.
+    pub(crate) fn map_sealed(&mut self, file_id: u32) -> io::Result<()> {
+        self.maps.remove(&file_id);
+        if self.options.read_path != ReadPath::Mmap {
+            return Ok(());
+        }
+        if let Some(map) = self.sealed.get(&file_id).map(T::map).transpose()?.flatten() {
+            self.maps.insert(file_id, Arc::new(map));
+        }
+        Ok(())
+    }
+
+    /// The bytes of the record a keydir entry points at, straight from the segment's
+    /// map. `None` if the segment is not mapped, or the map is shorter than the entry
+    /// says, in which case reading the file reports the error.
+    pub(crate) fn mapped_entry(&self, entry: &KeyDirEntry) -> Option<&[u8]> {
+        let map = self.maps.get(&entry.file_id)?;
+        let start = usize::try_from(entry.offset).ok()?;
+        let end = start.checked_add(usize::try_from(entry.length).ok()?)?;
+        map.get(start..end)
+    }
+}
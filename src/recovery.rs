@@ -0,0 +1,120 @@
+use crate::{claimed_record_len, parse_record_from_buffer, FileIO, RecordFormat, Result};
+
+/// The CRC-32 polynomial, bit-reversed, which is how the checksums are computed.
+const CRC_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Whether a whole record with a valid checksum starts anywhere from `from` to the end
+/// of `file`.
+///
+/// A long torn record has a possible start at every byte in it. Most are ruled out by
+/// their flags, or by lengths that run past the end, without reading any further. The
+/// checksum of each of the rest is worked out from the checksums of the tail up to its
+/// start and its end, so no byte is checksummed twice.
+pub(crate) fn record_follows<T: FileIO>(file: &T, format: RecordFormat, from: u64) -> Result<bool> {
+    let mut tail = vec![0; file.len()?.saturating_sub(from) as usize];
+    file.read_at(&mut tail, from)?;
+    let parses = |start: usize, len: usize| {
+        parse_record_from_buffer(&tail[start..start + len], format).is_ok()
+    };
+    if format == RecordFormat::V1 {
+        // A v1 record is at most a few hundred bytes long, so each start is parsed.
+        return Ok((0..tail.len()).any(|start| {
+            claimed_record_len(&tail[start..], format).is_some_and(|len| parses(start, len))
+        }));
+    }
+
+    let prefixes = prefix_checksums(&tail);
+    let shifts = ShiftTable::new();
+    Ok((0..tail.len()).any(|start| {
+        let Some(len) = claimed_record_len(&tail[start..], format) else {
+            return false;
+        };
+        // The checksum covers everything in the record before it.
+        let end = start + len - 4;
+        let stored = u32::from_le_bytes(tail[end..end + 4].try_into().unwrap());
+        let computed = prefixes[end] ^ multiply(prefixes[start], shifts.get(end - start));
+        computed == stored && parses(start, len)
+    }))
+}
+
+/// The checksum of every prefix of `bytes`, from the empty one to all of them.
+fn prefix_checksums(bytes: &[u8]) -> Vec<u32> {
+    let table = byte_table();
+    let mut checksums = Vec::with_capacity(bytes.len() + 1);
+    let mut state = !0u32;
+    checksums.push(!state);
+    for &byte in bytes {
+        state = table[((state ^ byte as u32) & 0xFF) as usize] ^ (state >> 8);
+        checksums.push(!state);
+    }
+    checksums
+}
+
+/// The state change for every byte value, as in any table-driven CRC-32.
+fn byte_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    for (byte, entry) in table.iter_mut().enumerate() {
+        let mut value = byte as u32;
+        for _ in 0..8 {
+            value = if value & 1 != 0 {
+                (value >> 1) ^ CRC_POLYNOMIAL
+            } else {
+                value >> 1
+            };
+        }
+        *entry = value;
+    }
+    table
+}
+
+// The checksum of `a` followed by `b` is the checksum of `a` times x^(8 * len(b)),
+// xor the checksum of `b`, with the polynomials in the same bit-reversed form as the
+// checksums and multiplied modulo the CRC polynomial. So the checksum of the bytes
+// between two prefixes follows from the checksums of the prefixes. This is the
+// arithmetic zlib's `crc32_combine` uses.
+
+/// `a` times `b` modulo the CRC polynomial.
+fn multiply(a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    // The top bit stands for x^0, so each shift moves on to the next power of x in `a`
+    // and multiplies `b` by x.
+    let mut rest = a;
+    while rest != 0 {
+        if rest & (1 << 31) != 0 {
+            product ^= b;
+        }
+        rest <<= 1;
+        b = if b & 1 != 0 {
+            (b >> 1) ^ CRC_POLYNOMIAL
+        } else {
+            b >> 1
+        };
+    }
+    product
+}
+
+/// x^(8 * n) modulo the CRC polynomial for any `n` below 2^32, as the product of one
+/// entry per byte of `n`.
+struct ShiftTable([[u32; 256]; 4]);
+
+impl ShiftTable {
+    fn new() -> Self {
+        let mut table = [[0; 256]; 4];
+        // x^8, then x^(8 * 256), x^(8 * 256^2) and x^(8 * 256^3).
+        let mut base = 1 << 23;
+        for row in table.iter_mut() {
+            row[0] = 1 << 31;
+            for i in 1..256 {
+                row[i] = multiply(row[i - 1], base);
+            }
+            base = multiply(row[255], base);
+        }
+        ShiftTable(table)
+    }
+
+    fn get(&self, n: usize) -> u32 {
+        (0..4).fold(1 << 31, |power, i| {
+            multiply(power, self.0[i][(n >> (8 * i)) & 0xFF])
+        })
+    }
+}
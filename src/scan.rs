@@ -0,0 +1,193 @@
+use std::{collections::btree_map, ops::Bound};
+
+use crate::{handle::StorageHandle, FileIO, KeyDirEntry, Result, SStStorage};
+
+/// Live keys in a key range, in ascending order, or descending after `rev`.
+///
+/// Keys come from the keydir, and each value is only read from its segment when the
+/// iterator reaches it. Keys that have expired are skipped.
+pub struct Scan<'a, T: FileIO> {
+    storage: &'a SStStorage<T>,
+    keys: Keys<'a>,
+}
+
+/// Like `Scan`, but yields keys only and never touches the segments.
+pub struct Keys<'a> {
+    range: btree_map::Range<'a, Vec<u8>, KeyDirEntry>,
+    now: u64,
+}
+
+/// A scan through a `StorageHandle`, which it holds on to instead of borrowing the
+/// storage.
+///
+/// The storage is only shared for each step, so writes carry on in between. A key
+/// written or deleted ahead of the cursor is seen as it is when the cursor gets there.
+pub struct Cursor<T: FileIO> {
+    storage: StorageHandle<T>,
+    // What is left of the range. Each step moves one end past the key it returned.
+    front: Bound<Vec<u8>>,
+    back: Bound<Vec<u8>>,
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Iterates over the live keys between `start` and `end`.
+    pub fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Scan<'_, T> {
+        // `BTreeMap::range` panics on a range that ends before it starts. Nothing sorts
+        // before the empty key, so this range is empty instead.
+        let range = if is_empty_range(start, end) {
+            self.index.range::<[u8], _>((Bound::Unbounded, Bound::Excluded(&[][..])))
+        } else {
+            self.index.range::<[u8], _>((start, end))
+        };
+        Scan {
+            storage: self,
+            keys: Keys {
+                range,
+                now: self.now(),
+            },
+        }
+    }
+
+    /// Iterates over the live keys that start with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan<'_, T> {
+        let end = prefix_end(prefix);
+        self.scan(Bound::Included(prefix), end.as_ref().map(Vec::as_slice))
+    }
+}
+
+fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => {
+            start >= end
+        }
+        _ => false,
+    }
+}
+
+// The end of the range of keys that start with `prefix`: the smallest key greater than
+// all of them, which does not exist if the prefix is empty or all 0xff.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match prefix.iter().rposition(|&byte| byte != u8::MAX) {
+        Some(last) => {
+            let mut end = prefix[..=last].to_vec();
+            end[last] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    }
+}
+
+impl<'a, T: FileIO> Scan<'a, T> {
+    /// Drops the values and yields the keys only.
+    pub fn keys(self) -> Keys<'a> {
+        self.keys
+    }
+
+    fn with_value(&self, (key, entry): (&[u8], &KeyDirEntry)) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok((key.to_vec(), self.storage.read_value(entry)?))
+    }
+}
+
+impl<T: FileIO> Iterator for Scan<'_, T> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let live = self.keys.next_live()?;
+        Some(self.with_value(live))
+    }
+}
+
+impl<T: FileIO> DoubleEndedIterator for Scan<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let live = self.keys.next_back_live()?;
+        Some(self.with_value(live))
+    }
+}
+
+impl<'a> Keys<'a> {
+    fn next_live(&mut self) -> Option<(&'a [u8], &'a KeyDirEntry)> {
+        let now = self.now;
+        self.range
+            .find(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.as_slice(), entry))
+    }
+
+    fn next_back_live(&mut self) -> Option<(&'a [u8], &'a KeyDirEntry)> {
+        let now = self.now;
+        self.range
+            .rfind(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.as_slice(), entry))
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_live().map(|(key, _)| key)
+    }
+}
+
+impl DoubleEndedIterator for Keys<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_live().map(|(key, _)| key)
+    }
+}
+
+impl<T: FileIO> Cursor<T> {
+    pub(crate) fn new(storage: StorageHandle<T>, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+        Cursor {
+            storage,
+            front: start.map(<[u8]>::to_vec),
+            back: end.map(<[u8]>::to_vec),
+        }
+    }
+
+    pub(crate) fn with_prefix(storage: StorageHandle<T>, prefix: &[u8]) -> Self {
+        Cursor {
+            storage,
+            front: Bound::Included(prefix.to_vec()),
+            back: prefix_end(prefix),
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let storage = match self.storage.shared() {
+            Ok(storage) => storage,
+            Err(e) => return Some(Err(e)),
+        };
+        let front = self.front.as_ref().map(Vec::as_slice);
+        let back = self.back.as_ref().map(Vec::as_slice);
+        let mut keys = storage.scan(front, back).keys();
+        let (key, entry) = if forward {
+            keys.next_live()?
+        } else {
+            keys.next_back_live()?
+        };
+        let value = storage.read_value(entry);
+        // Move past the key even if its value could not be read, so the cursor does
+        // not return the same error forever.
+        let key = key.to_vec();
+        if forward {
+            self.front = Bound::Excluded(key.clone());
+        } else {
+            self.back = Bound::Excluded(key.clone());
+        }
+        Some(value.map(|value| (key, value)))
+    }
+}
+
+impl<T: FileIO> Iterator for Cursor<T> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+impl<T: FileIO> DoubleEndedIterator for Cursor<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::PathBuf,
+};
+
+use crate::{open_file_read_write, Error, FileIO, Result};
+
+const DATA_FILE_EXTENSION: &str = "data";
+const MERGE_FILE_EXTENSION: &str = "merge";
+const HINT_FILE_EXTENSION: &str = "hint";
+const LOCK_FILE_NAME: &str = "LOCK";
+// The single file the first versions of the database appended to, before segments.
+const LEGACY_FILE_NAME: &str = "active/database.txt";
+
+/// A data directory made up of numbered segment files.
+///
+/// The segment with the highest id is the active one; every other segment is
+/// sealed and never written to again.
+pub trait SegmentDir<T: FileIO>: Send + Sync {
+    /// Opens the segment with the given id, creating it if it does not exist yet.
+    fn open_segment(&self, id: u32) -> io::Result<T>;
+
+    /// Returns the ids of every segment in the directory, in ascending order.
+    fn segment_ids(&self) -> io::Result<Vec<u32>>;
+
+    /// Deletes a segment. Its id is never reused.
+    fn remove_segment(&self, id: u32) -> io::Result<()>;
+
+    /// Creates an empty merge output. It is not a segment, and is not returned by
+    /// `segment_ids`, until it is committed.
+    fn create_merge_segment(&self, id: u32) -> io::Result<T>;
+
+    /// Turns a fully written merge output into the segment with the same id.
+    fn commit_merge_segment(&self, id: u32) -> io::Result<()>;
+
+    /// Deletes merge outputs left behind by a merge that never committed.
+    fn remove_uncommitted_merges(&self) -> io::Result<()>;
+
+    /// Creates, or empties, the hint file that belongs to a segment.
+    fn create_hint(&self, id: u32) -> io::Result<T>;
+
+    /// Opens the hint file for a segment, if it has one.
+    fn open_hint(&self, id: u32) -> io::Result<Option<T>>;
+
+    /// Deletes the hint file for a segment, if it has one.
+    fn remove_hint(&self, id: u32) -> io::Result<()>;
+
+    /// Makes files created, renamed or deleted so far survive a crash.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// Segment files stored as `<id>.data` inside a directory on disk.
+pub struct FsSegmentDir {
+    path: PathBuf,
+    // Locked for as long as the directory is open, so that only one process appends
+    // to it. The operating system releases the lock if the process dies.
+    _lock: File,
+}
+
+impl FsSegmentDir {
+    /// Opens the directory, creating it if it does not exist. Fails with
+    /// `Error::Locked` if another process already has it open.
+    ///
+    /// A directory written before segments existed has its `active/database.txt` moved
+    /// into place as segment 1.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = open_file_read_write(path.join(LOCK_FILE_NAME))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(Error::Locked { path }),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let dir = FsSegmentDir { path, _lock: lock };
+        dir.adopt_legacy_file()?;
+        Ok(dir)
+    }
+
+    // The legacy file holds v1 records with no header, which is how a segment without
+    // one is read. It can only become segment 1 if there are no segments yet.
+    fn adopt_legacy_file(&self) -> Result<()> {
+        let legacy = self.path.join(LEGACY_FILE_NAME);
+        if !legacy.try_exists()? {
+            return Ok(());
+        }
+        if !self.segment_ids()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is left over from an older version, but {} already has segments",
+                    legacy.display(),
+                    self.path.display()
+                ),
+            )
+            .into());
+        }
+        fs::rename(&legacy, self.segment_path(1))?;
+        if let Some(parent) = legacy.parent() {
+            // Only removed if nothing else was kept in it.
+            let _ = fs::remove_dir(parent);
+        }
+        Ok(self.sync()?)
+    }
+
+    fn segment_path(&self, id: u32) -> PathBuf {
+        self.file_path(id, DATA_FILE_EXTENSION)
+    }
+
+    fn file_path(&self, id: u32, extension: &str) -> PathBuf {
+        self.path.join(format!("{:06}.{}", id, extension))
+    }
+
+    /// Ids of the files in the directory named `<id>.<extension>`, in ascending order.
+    fn file_ids(&self, extension: &str) -> io::Result<Vec<u32>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+                continue;
+            }
+            // Anything that is not named after a segment id is not ours to load.
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+impl SegmentDir<File> for FsSegmentDir {
+    fn open_segment(&self, id: u32) -> io::Result<File> {
+        open_file_read_write(self.segment_path(id))
+    }
+
+    fn segment_ids(&self) -> io::Result<Vec<u32>> {
+        self.file_ids(DATA_FILE_EXTENSION)
+    }
+
+    fn remove_segment(&self, id: u32) -> io::Result<()> {
+        fs::remove_file(self.segment_path(id))
+    }
+
+    fn create_merge_segment(&self, id: u32) -> io::Result<File> {
+        create_truncated(self.file_path(id, MERGE_FILE_EXTENSION))
+    }
+
+    fn commit_merge_segment(&self, id: u32) -> io::Result<()> {
+        // A rename within one directory is atomic, so the segment is either missing or complete.
+        fs::rename(
+            self.file_path(id, MERGE_FILE_EXTENSION),
+            self.segment_path(id),
+        )
+    }
+
+    fn remove_uncommitted_merges(&self) -> io::Result<()> {
+        for id in self.file_ids(MERGE_FILE_EXTENSION)? {
+            fs::remove_file(self.file_path(id, MERGE_FILE_EXTENSION))?;
+        }
+        Ok(())
+    }
+
+    fn create_hint(&self, id: u32) -> io::Result<File> {
+        create_truncated(self.file_path(id, HINT_FILE_EXTENSION))
+    }
+
+    fn open_hint(&self, id: u32) -> io::Result<Option<File>> {
+        match File::open(self.file_path(id, HINT_FILE_EXTENSION)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove_hint(&self, id: u32) -> io::Result<()> {
+        match fs::remove_file(self.file_path(id, HINT_FILE_EXTENSION)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        File::open(&self.path)?.sync_all()
+    }
+}
+
+fn create_truncated(path: PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    expiry::{ExpiryQueue, Ttl},
+    scan::Scan,
+    segment::SegmentDir,
+    unix_now, FileIO, Result, SStStorage,
+};
+
+/// A read-only view of the storage as it was when the snapshot was taken.
+///
+/// It has its own copy of the keydir and its own handles to the segments, so reads
+/// through it give the same answer however many times they are repeated, while writes,
+/// expiry cleanup and merges carry on. Keys expire as of when it was taken.
+///
+/// A merge does not delete a segment that a live snapshot reads from. The segment stays
+/// on disk until the last snapshot that needs it is dropped.
+pub struct Snapshot<T: FileIO> {
+    storage: SStStorage<T>,
+    // Declared after `storage`, so the segment handles are closed before the pin is
+    // released and the segments can be deleted.
+    _pin: Pin<T>,
+}
+
+/// Which segments live snapshots read from, and the merge inputs that are waiting for
+/// them before they are deleted.
+pub(crate) struct SnapshotRegistry<T: FileIO> {
+    // `None` when the storage wraps a single file, which is never merged.
+    dir: Option<Arc<dyn SegmentDir<T>>>,
+    state: Mutex<Pins>,
+}
+
+#[derive(Default)]
+struct Pins {
+    // How many live snapshots read from each segment.
+    readers: BTreeMap<u32, usize>,
+    // Merge inputs that are still on disk, oldest first.
+    retired: VecDeque<u32>,
+}
+
+/// Keeps segments on disk for as long as a snapshot is alive.
+struct Pin<T: FileIO> {
+    registry: Arc<SnapshotRegistry<T>>,
+    ids: Vec<u32>,
+}
+
+impl<T: FileIO> SStStorage<T> {
+    /// Takes a snapshot of the storage as it is now.
+    pub fn snapshot(&self) -> Result<Snapshot<T>> {
+        let mut ids: Vec<u32> = self.sealed.keys().copied().collect();
+        ids.push(self.active_id);
+        // Pinned before anything is copied, so nothing the copy reads can be deleted.
+        self.snapshots.pin(&ids);
+        let pin = Pin {
+            registry: self.snapshots.clone(),
+            ids,
+        };
+
+        let mut sealed = BTreeMap::new();
+        for (&id, file) in &self.sealed {
+            sealed.insert(id, file.try_clone()?);
+        }
+        // Nothing is ever appended through the copy. The active segment is only read at
+        // offsets that were written before the snapshot was taken.
+        let storage = SStStorage {
+            index: self.index.clone(),
+            active: self.active.try_clone()?,
+            active_id: self.active_id,
+            active_hints: Vec::new(),
+            sealed,
+            maps: self.maps.clone(),
+            headers: self.headers.clone(),
+            dir: None,
+            options: Default::default(),
+            unsynced_bytes: 0,
+            flusher: None,
+            append_offset: None,
+            expiry: ExpiryQueue::default(),
+            snapshots: self.snapshots.clone(),
+            frozen_at: Some(self.now()),
+            compression_stats: Default::default(),
+        };
+        Ok(Snapshot { storage, _pin: pin })
+    }
+}
+
+impl<T: FileIO> Snapshot<T> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.storage.read(key)
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Ttl {
+        self.storage.ttl(key)
+    }
+
+    /// Iterates over the keys between `start` and `end` that were live when the
+    /// snapshot was taken.
+    pub fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Scan<'_, T> {
+        self.storage.scan(start, end)
+    }
+
+    /// Like `scan`, over the keys that start with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan<'_, T> {
+        self.storage.scan_prefix(prefix)
+    }
+
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub fn taken_at(&self) -> u64 {
+        self.storage.frozen_at.unwrap_or_else(unix_now)
+    }
+}
+
+impl<T: FileIO> SnapshotRegistry<T> {
+    pub(crate) fn new(dir: Option<Arc<dyn SegmentDir<T>>>) -> Self {
+        SnapshotRegistry {
+            dir,
+            state: Mutex::new(Pins::default()),
+        }
+    }
+
+    // The counts stay consistent even if a thread panicked holding the lock, since
+    // every update is a single step.
+    fn state(&self) -> MutexGuard<'_, Pins> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pin(&self, ids: &[u32]) {
+        let mut state = self.state();
+        for &id in ids {
+            *state.readers.entry(id).or_insert(0) += 1;
+        }
+    }
+
+    fn unpin(&self, ids: &[u32]) -> Result<()> {
+        let mut state = self.state();
+        for id in ids {
+            if let Some(count) = state.readers.get_mut(id) {
+                *count -= 1;
+                if *count == 0 {
+                    state.readers.remove(id);
+                }
+            }
+        }
+        self.remove_unread(&mut state)
+    }
+
+    /// Hands over the inputs of a merge, which are no longer part of the storage, to
+    /// be deleted once no snapshot reads from them.
+    pub(crate) fn retire(&self, ids: Vec<u32>) -> Result<()> {
+        let mut state = self.state();
+        state.retired.extend(ids);
+        self.remove_unread(&mut state)
+    }
+
+    // Deletes retired segments oldest first, and stops at the first one a snapshot still
+    // reads from: a tombstone is only deleted after every older value it shadowed.
+    fn remove_unread(&self, state: &mut Pins) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        while let Some(&id) = state.retired.front() {
+            if state.readers.contains_key(&id) {
+                break;
+            }
+            dir.remove_hint(id)?;
+            dir.remove_segment(id)?;
+            state.retired.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl<T: FileIO> Drop for Pin<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.registry.unpin(&self.ids) {
+            log::warn!("Failed to delete merged segments after a snapshot: {}", e);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use crate::{handle::StorageHandle, Error, FileIO, KeyDirEntry, Result, WriteBatch};
+
+/// A read-modify-write over several keys that commits only if nothing it read has
+/// changed since.
+///
+/// Reads go to the storage, except for keys the transaction has written itself. Puts
+/// and deletes are buffered until `commit`, which writes them as one `WriteBatch`.
+/// Nothing is locked in between, so other writers carry on and a conflict is only
+/// found at commit.
+///
+/// A key's version is the keydir entry it was read from. Any write, delete or new
+/// expiry of the key moves or changes that entry, and so does a merge that copies
+/// it, so a merge can make a commit fail that would otherwise have gone through.
+pub struct Transaction<T: FileIO> {
+    storage: StorageHandle<T>,
+    // The entry each key pointed at when it was first read, or `None` if it did not
+    // exist then.
+    reads: BTreeMap<Vec<u8>, Option<KeyDirEntry>>,
+    // The latest put, or `None` for a delete, of each key written.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<T: FileIO> Transaction<T> {
+    pub(crate) fn new(storage: StorageHandle<T>) -> Self {
+        Transaction {
+            storage,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Reads `key`, as the transaction has written it or else as it is in the storage.
+    /// A key read from the storage is checked for changes when the transaction commits.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let storage = self.storage.shared()?;
+        let entry = storage.live_entry(key).copied();
+        let value = match &entry {
+            Some(entry) => Some(storage.read_value(entry)?),
+            None => None,
+        };
+        // Keep the version from the first read, so a change between two reads of the same
+        // key still conflicts.
+        self.reads.entry(key.to_vec()).or_insert(entry);
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Writes everything the transaction put or deleted, as long as every key it read
+    /// is as it was then. Fails with `Error::Conflict`, and writes nothing, otherwise.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        // The storage is held exclusively from the check until the batch is written, so
+        // no other write can slip in between.
+        let mut storage = self.storage.exclusive()?;
+        for (key, version) in &self.reads {
+            if storage.live_entry(key) != version.as_ref() {
+                return Err(Error::Conflict { key: key.clone() });
+            }
+        }
+        storage.write_batch(&batch)
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    fs::File,
+    io::{self, SeekFrom},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::{
+    mmap::{self, SegmentMap},
+    segment::{FsSegmentDir, SegmentDir},
+    FileIO, Result,
+};
+
+/// Size of the submission queue.
+const RING_ENTRIES: u32 = 64;
+
+/// Operations submitted in one round. Longer lists are split into several, and each
+/// round leaves the other half of the queue free to cancel it.
+const ROUND_LEN: usize = RING_ENTRIES as usize / 2;
+
+/// `user_data` of the entries that cancel others.
+const CANCEL: u64 = u64::MAX;
+
+/// One io_uring instance, shared by every file of a `UringSegmentDir`.
+struct Ring {
+    ring: Mutex<IoUring>,
+}
+
+/// A file on disk whose reads, writes and syncs go through io_uring. The reads of
+/// `read_many`, and the write and sync of `write_at_and_sync`, are submitted together.
+///
+/// Without a ring, because the kernel is too old or does not allow io_uring, every
+/// call is the blocking one `File` makes.
+pub struct UringFile {
+    file: File,
+    ring: Option<Arc<Ring>>,
+}
+
+/// Segment files on disk, laid out like `FsSegmentDir`, that are all `UringFile`s
+/// sharing one ring.
+pub struct UringSegmentDir {
+    dir: FsSegmentDir,
+    ring: Option<Arc<Ring>>,
+}
+
+impl Ring {
+    fn new() -> Option<Arc<Ring>> {
+        let ring = IoUring::new(RING_ENTRIES).ok()?;
+        Some(Arc::new(Ring {
+            ring: Mutex::new(ring),
+        }))
+    }
+
+    /// Submits `entries` and waits for every one of them to complete. Returns the
+    /// result of each, in the same order.
+    ///
+    /// If the ring fails while they are in flight, they are cancelled and waited for,
+    /// so the kernel is done with their buffers and the ring is empty when this returns.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptors and buffers the entries point at must stay valid until
+    /// this returns. Linked entries must not straddle a multiple of `ROUND_LEN`.
+    unsafe fn run(&self, entries: &[squeue::Entry]) -> io::Result<Vec<i32>> {
+        let mut ring = self.ring.lock().map_err(|_| poisoned())?;
+        let mut results = vec![0; entries.len()];
+        for (round, chunk) in entries.chunks(ROUND_LEN).enumerate() {
+            let first = round * ROUND_LEN;
+            let tagged: Vec<squeue::Entry> = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| entry.clone().user_data((first + i) as u64))
+                .collect();
+            // All or nothing, so a full queue leaves nothing behind to be submitted later.
+            ring.submission()
+                .push_multiple(&tagged)
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+
+            let mut done = vec![false; chunk.len()];
+            let mut left = chunk.len();
+            let mut cancels_left = 0;
+            let mut failure = None;
+            while left > 0 || cancels_left > 0 {
+                match ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(e) if is_transient(&e) => {}
+                    Err(e) if failure.is_none() => {
+                        let cancels: Vec<squeue::Entry> = (0..chunk.len())
+                            .filter(|&i| !done[i])
+                            .map(|i| {
+                                opcode::AsyncCancel::new((first + i) as u64)
+                                    .build()
+                                    .user_data(CANCEL)
+                            })
+                            .collect();
+                        // A round takes up at most half the queue, so there is room to
+                        // cancel all of it.
+                        if ring.submission().push_multiple(&cancels).is_err() {
+                            std::process::abort();
+                        }
+                        cancels_left = cancels.len();
+                        failure = Some(e);
+                    }
+                    // Nothing can be cancelled or waited for any more, and returning would
+                    // leave the kernel writing to buffers that are about to be freed.
+                    Err(_) => std::process::abort(),
+                }
+                for completion in ring.completion() {
+                    match completion.user_data() {
+                        CANCEL => cancels_left -= 1,
+                        id => {
+                            results[id as usize] = completion.result();
+                            if !std::mem::replace(&mut done[id as usize - first], true) {
+                                left -= 1;
+                            }
+                        }
+                    }
+                }
+            }
+            if let Some(e) = failure {
+                return Err(e);
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Errors from submitting or waiting that go away when it is tried again.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// The number of bytes an operation transferred, or the error it failed with.
+fn transferred(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+impl UringFile {
+    /// Wraps `file` with a ring of its own, or none if io_uring is unavailable.
+    pub fn new(file: File) -> Self {
+        UringFile {
+            file,
+            ring: Ring::new(),
+        }
+    }
+
+    /// Whether calls go through io_uring rather than falling back to `File`.
+    pub fn uses_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    fn write_entry(&self, buf: &[u8], offset: u64) -> squeue::Entry {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        opcode::Write::new(types::Fd(self.file.as_raw_fd()), buf.as_ptr(), len)
+            .offset(offset)
+            .build()
+    }
+
+    // The kernel may write fewer bytes than asked, like `pwrite`. The rest is written
+    // with a blocking call.
+    fn finish_write(&mut self, buf: &[u8], offset: u64, written: usize) -> io::Result<()> {
+        match written {
+            0 if !buf.is_empty() => Err(io::ErrorKind::WriteZero.into()),
+            n if n < buf.len() => FileIO::write_at(&mut self.file, &buf[n..], offset + n as u64),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FileIO for UringFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        FileIO::write(&mut self.file, buf)
+    }
+
+    fn seek_from(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        FileIO::seek_from(&mut self.file, pos)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        FileIO::set_len(&mut self.file, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_many(&mut [(offset, buf)])
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => return FileIO::write_at(&mut self.file, buf, offset),
+        };
+        // SAFETY: `buf` and the file outlive the call.
+        let results = unsafe { ring.run(&[self.write_entry(buf, offset)])? };
+        let written = transferred(results[0])?;
+        self.finish_write(buf, offset, written)
+    }
+
+    fn read_many(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => {
+                for (offset, buf) in reads.iter_mut() {
+                    FileIO::read_at(&self.file, buf, *offset)?;
+                }
+                return Ok(());
+            }
+        };
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entries: Vec<squeue::Entry> = reads
+            .iter_mut()
+            .map(|(offset, buf)| {
+                let len = buf.len().min(u32::MAX as usize) as u32;
+                opcode::Read::new(fd, buf.as_mut_ptr(), len)
+                    .offset(*offset)
+                    .build()
+            })
+            .collect();
+        // SAFETY: the buffers in `reads` and the file outlive the call.
+        let results = unsafe { ring.run(&entries)? };
+        for ((offset, buf), result) in reads.iter_mut().zip(results) {
+            // A short read, at the end of the file or otherwise, is finished with a
+            // blocking one, which fails if the file really is too short.
+            let read = transferred(result)?;
+            if read < buf.len() {
+                FileIO::read_at(&self.file, &mut buf[read..], *offset + read as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at_and_sync(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => return FileIO::write_at_and_sync(&mut self.file, buf, offset),
+        };
+        // The sync only starts once the write has completed in full. If the write
+        // fails or comes up short, the sync is cancelled.
+        let entries = [
+            self.write_entry(buf, offset).flags(squeue::Flags::IO_LINK),
+            opcode::Fsync::new(types::Fd(self.file.as_raw_fd())).build(),
+        ];
+        // SAFETY: `buf` and the file outlive the call.
+        let results = unsafe { ring.run(&entries)? };
+        let written = transferred(results[0])?;
+        if written < buf.len() {
+            self.finish_write(buf, offset, written)?;
+            return FileIO::sync(&mut self.file);
+        }
+        transferred(results[1]).map(drop)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        FileIO::len(&self.file)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => return FileIO::sync(&mut self.file),
+        };
+        let entry = opcode::Fsync::new(types::Fd(self.file.as_raw_fd())).build();
+        // SAFETY: the file outlives the call.
+        let results = unsafe { ring.run(&[entry])? };
+        transferred(results[0]).map(drop)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(UringFile {
+            file: self.file.try_clone()?,
+            ring: self.ring.clone(),
+        })
+    }
+
+    fn map(&self) -> io::Result<Option<SegmentMap>> {
+        mmap::map_file(&self.file).map(Some)
+    }
+}
+
+impl UringSegmentDir {
+    /// Opens the directory like `FsSegmentDir::new`, with a ring for its files. If
+    /// io_uring is unavailable the files make blocking calls instead.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let mut dir = UringSegmentDir::blocking(path)?;
+        dir.ring = Ring::new();
+        Ok(dir)
+    }
+
+    /// Opens the directory without a ring, as `new` does when io_uring is unavailable.
+    pub fn blocking(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(UringSegmentDir {
+            dir: FsSegmentDir::new(path)?,
+            ring: None,
+        })
+    }
+
+    /// Whether its files go through io_uring rather than falling back to `File`.
+    pub fn uses_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    fn wrap(&self, file: File) -> UringFile {
+        UringFile {
+            file,
+            ring: self.ring.clone(),
+        }
+    }
+}
+
+impl SegmentDir<UringFile> for UringSegmentDir {
+    fn open_segment(&self, id: u32) -> io::Result<UringFile> {
+        Ok(self.wrap(self.dir.open_segment(id)?))
+    }
+
+    fn segment_ids(&self) -> io::Result<Vec<u32>> {
+        self.dir.segment_ids()
+    }
+
+    fn remove_segment(&self, id: u32) -> io::Result<()> {
+        self.dir.remove_segment(id)
+    }
+
+    fn create_merge_segment(&self, id: u32) -> io::Result<UringFile> {
+        Ok(self.wrap(self.dir.create_merge_segment(id)?))
+    }
+
+    fn commit_merge_segment(&self, id: u32) -> io::Result<()> {
+        self.dir.commit_merge_segment(id)
+    }
+
+    fn remove_uncommitted_merges(&self) -> io::Result<()> {
+        self.dir.remove_uncommitted_merges()
+    }
+
+    fn create_hint(&self, id: u32) -> io::Result<UringFile> {
+        Ok(self.wrap(self.dir.create_hint(id)?))
+    }
+
+    fn open_hint(&self, id: u32) -> io::Result<Option<UringFile>> {
+        Ok(self.dir.open_hint(id)?.map(|file| self.wrap(file)))
+    }
+
+    fn remove_hint(&self, id: u32) -> io::Result<()> {
+        self.dir.remove_hint(id)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.dir.sync()
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("io_uring lock poisoned: a thread panicked while holding it")
+}